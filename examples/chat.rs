@@ -12,14 +12,16 @@ async fn main() {
     let config: MessageConfig = MessageConfigBuilder::new()
         .set_max_tokens(999)
         .set_model("GigaChat-Max")
-        .build();
+        .build()
+        .unwrap();
 
     let client: GigaClient = ClientBuilder::new()
         .set_basic_token(&std::env::var("GIGACHAT_TOKEN").unwrap())
         .set_msg_cfg(config)
-        .build();
+        .build()
+        .unwrap();
 
-    let mut chat: Chat = Chat::new(client);
+    let mut chat: Chat = Chat::new(client).with_system_prompt("You are a helpful assistant");
     // or -> let mut chat: Chat = Chat::new_cached(client);
 
     chat.get_client_mut().reset_msg_config(None); // Set to default
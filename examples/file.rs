@@ -9,12 +9,14 @@ async fn main() {
     let config: MessageConfig = MessageConfigBuilder::new()
         .set_max_tokens(999)
         .set_model("GigaChat-Pro")
-        .build();
+        .build()
+        .unwrap();
 
-    let mut client: GigaClient = ClientBuilder::new()
+    let client: GigaClient = ClientBuilder::new()
         .set_basic_token(&std::env::var("GIGACHAT_TOKEN").unwrap())
         .set_msg_cfg(config)
-        .build();
+        .build()
+        .unwrap();
 
     let file_info = client.upload_file("path/to/file.png".into()).await.unwrap();
 
@@ -0,0 +1,46 @@
+use gigalib::{
+    controllers::{
+        chat::Chat,
+        client::{ClientBuilder, GigaClient},
+    },
+    http::function::FunctionDefinition,
+};
+use serde_json::{json, Value};
+
+#[tokio::main]
+async fn main() {
+    let client: GigaClient = ClientBuilder::new()
+        .set_basic_token(&std::env::var("GIGACHAT_TOKEN").unwrap())
+        .build()
+        .unwrap();
+
+    let mut chat: Chat = Chat::new(client);
+
+    let weather = FunctionDefinition::new(
+        "weather_forecast",
+        json!({
+            "type": "object",
+            "properties": {
+                "location": { "type": "string", "description": "City, e.g. Moscow" }
+            },
+            "required": ["location"]
+        }),
+    )
+    .set_description("Returns the current weather in a city")
+    .add_example(
+        "What's the weather in Kazan?",
+        json!({ "location": "Kazan" }),
+    );
+
+    // The handler gets the arguments the model generated, its result is sent back to the model
+    chat.register_function(weather, |args: Value| async move {
+        let location = args["location"].as_str().unwrap_or("unknown").to_owned();
+        Ok::<_, std::io::Error>(json!({ "location": location, "temperature": 21 }))
+    });
+
+    let resp = chat
+        .run("Should I take a jacket in Moscow today?".into())
+        .await
+        .unwrap();
+    println!("{}", resp.content);
+}
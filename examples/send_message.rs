@@ -9,21 +9,26 @@ async fn main() {
     let config: MessageConfig = MessageConfigBuilder::new()
         .set_max_tokens(999)
         .set_model("GigaChat-Pro")
-        .build();
+        .build()
+        .unwrap();
 
-    let mut client: GigaClient = ClientBuilder::new()
+    let client: GigaClient = ClientBuilder::new()
         .set_basic_token(&std::env::var("GIGACHAT_TOKEN").unwrap())
         .set_msg_cfg(config)
-        .build();
+        .build()
+        .unwrap();
 
     // There are a lot of different ways to pass a message into send_message()
     let response: Message = client.send_message("hello!".into()).await.unwrap();
+    println!("{}", response.content);
     let response = client
         .send_message(String::from("Hello!").into())
         .await
         .unwrap();
+    println!("{}", response.content);
     let response = client
         .send_message(Message::from_str("hello"))
         .await
         .unwrap();
+    println!("{}", response.content);
 }
@@ -0,0 +1,31 @@
+use std::io::Write;
+
+use futures::StreamExt;
+use gigalib::controllers::{
+    chat::Chat,
+    client::{ClientBuilder, GigaClient},
+};
+
+#[tokio::main]
+async fn main() {
+    let client: GigaClient = ClientBuilder::new()
+        .set_basic_token(&std::env::var("GIGACHAT_TOKEN").unwrap())
+        .build()
+        .unwrap();
+
+    let mut chat: Chat = Chat::new(client);
+
+    loop {
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input).unwrap();
+
+        // Fragments are printed as soon as they arrive, the full answer ends up in the message history
+        let stream = chat.send_message_stream(input.into()).await.unwrap();
+        futures::pin_mut!(stream);
+        while let Some(fragment) = stream.next().await {
+            print!("{}", fragment.unwrap().content);
+            std::io::stdout().flush().unwrap();
+        }
+        println!();
+    }
+}
@@ -0,0 +1,45 @@
+use gigalib::{
+    controllers::{
+        chat::Chat,
+        client::{ClientBuilder, GigaClient},
+    },
+    http::function::FunctionDefinition,
+    schemars::{self, JsonSchema},
+};
+use serde::Deserialize;
+use serde_json::json;
+
+/// Returns the current weather in a city
+#[derive(Deserialize, JsonSchema)]
+struct WeatherForecast {
+    /// City, e.g. Moscow
+    location: String,
+    /// Units of the temperature, celsius by default
+    units: Option<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    // The struct fields and doc comments become the function schema
+    let definition = FunctionDefinition::from_type::<WeatherForecast>("weather_forecast");
+    println!("{}", serde_json::to_string_pretty(&definition).unwrap());
+
+    let client: GigaClient = ClientBuilder::new()
+        .set_basic_token(&std::env::var("GIGACHAT_TOKEN").unwrap())
+        .build()
+        .unwrap();
+
+    let mut chat: Chat = Chat::new(client);
+    chat.register_typed_function("weather_forecast", |args: WeatherForecast| async move {
+        let units = args.units.unwrap_or_else(|| "celsius".to_owned());
+        Ok::<_, std::io::Error>(
+            json!({ "location": args.location, "temperature": 21, "units": units }),
+        )
+    });
+
+    let resp = chat
+        .run("Should I take a jacket in Moscow today?".into())
+        .await
+        .unwrap();
+    println!("{}", resp.content);
+}
@@ -1,8 +1,39 @@
+use std::fmt::Display;
+
 use serde::Deserialize;
 
 /// GigaChat OAuth token, that is needed for making requests to the API
 #[derive(Clone, Deserialize)]
 pub struct AccessToken {
     pub access_token: String,
+    /// Unix timestamp in milliseconds
     pub expires_at: u64,
 }
+
+/// OAuth scope, which depends on the kind of the GigaChat API account
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scope {
+    /// Personal account
+    #[default]
+    Pers,
+    /// Business account with prepaid or postpaid billing
+    B2B,
+    /// Corporate account
+    Corp,
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::Pers => {
+                write!(f, "GIGACHAT_API_PERS")
+            }
+            Self::B2B => {
+                write!(f, "GIGACHAT_API_B2B")
+            }
+            Self::Corp => {
+                write!(f, "GIGACHAT_API_CORP")
+            }
+        }
+    }
+}
@@ -1,14 +1,41 @@
+use std::{collections::HashMap, error::Error, future::Future};
+
+use async_stream::try_stream;
+use futures::{future::BoxFuture, FutureExt, Stream, StreamExt};
+use serde_json::Value;
 use uuid::Uuid;
 
-use crate::http::message::Message;
+use crate::{
+    error::{GigaError, Result},
+    http::{
+        function::FunctionDefinition,
+        message::{Message, Role},
+        response::{ChatResponse, Usage},
+    },
+};
 
 use super::client::GigaClient;
 
+/// How many function calls `Chat::run` makes before giving up by default
+const DEFAULT_MAX_STEPS: usize = 5;
+
+/// Async handler of a function call, gets the arguments the model generated and returns the result
+pub type FunctionHandler = Box<
+    dyn Fn(Value) -> BoxFuture<'static, std::result::Result<Value, Box<dyn Error + Send + Sync>>>
+        + Send
+        + Sync,
+>;
+
 /// Used to make a chat, stores message history, makes caching messages possible, so a response comes faster
 pub struct Chat {
     client: GigaClient,
     message_history: Vec<Message>,
     cache_uuid: String,
+    usage: Usage,
+
+    // Functions executed by 'run'
+    functions: HashMap<String, (FunctionDefinition, FunctionHandler)>,
+    max_steps: usize,
 }
 
 impl Chat {
@@ -18,6 +45,9 @@ impl Chat {
             client,
             message_history: Vec::new(),
             cache_uuid: String::new(),
+            usage: Usage::default(),
+            functions: HashMap::new(),
+            max_steps: DEFAULT_MAX_STEPS,
         }
     }
     /// Create a cached version of chat
@@ -27,6 +57,24 @@ impl Chat {
             client,
             message_history: Vec::new(),
             cache_uuid: cache,
+            usage: Usage::default(),
+            functions: HashMap::new(),
+            max_steps: DEFAULT_MAX_STEPS,
+        }
+    }
+
+    /// Pins a system prompt at the start of the message history
+    pub fn with_system_prompt(mut self, prompt: &str) -> Self {
+        self.set_system_prompt(prompt);
+        self
+    }
+
+    /// Sets the system prompt, replacing the previous one if there is any
+    pub fn set_system_prompt(&mut self, prompt: &str) {
+        let system_msg = Message::new(prompt, Role::System);
+        match self.message_history.first_mut() {
+            Some(first) if first.role == Role::System => *first = system_msg,
+            _ => self.message_history.insert(0, system_msg),
         }
     }
 
@@ -41,26 +89,266 @@ impl Chat {
     }
 
     /// Sends a message and stores it in the message history
-    pub async fn send_message(&mut self, message: Message) -> anyhow::Result<Message> {
-        self.message_history.push(message.clone());
+    pub async fn send_message(&mut self, message: Message) -> Result<Message> {
+        self.send_message_full(message).await?.answer()
+    }
+
+    /// Same as `send_message`, but returns the whole response, including token usage and the finish reason
+    pub async fn send_message_full(&mut self, message: Message) -> Result<ChatResponse> {
+        self.push_message(message).await?;
 
         let resp = self
             .client
-            .send_messages(
-                self.message_history.clone(),
-                if self.cache_uuid.is_empty() {
-                    None
-                } else {
-                    Some(&self.cache_uuid)
-                },
-            )
+            .send_completion(self.message_history.clone(), self.cache_uuid())
             .await?;
-        self.message_history.push(resp.clone());
+        self.record(&resp)?;
         Ok(resp)
     }
 
+    /// Sends a message and streams the response fragments, the assembled response is stored in the message history once the stream completes
+    pub async fn send_message_stream(
+        &mut self,
+        message: Message,
+    ) -> Result<impl Stream<Item = Result<Message>> + '_> {
+        self.push_message(message).await?;
+
+        let chunks = self
+            .client
+            .stream_completion(self.message_history.clone(), self.cache_uuid())
+            .await?;
+
+        let mut assembled = ChatResponse::empty(&self.client.get_current_config().model);
+        let (history, usage) = (&mut self.message_history, &mut self.usage);
+        Ok(try_stream! {
+            futures::pin_mut!(chunks);
+
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk?;
+                let fragments: Vec<Message> = chunk
+                    .choices
+                    .iter()
+                    .map(|choice| choice.delta.clone().into())
+                    .collect();
+                assembled.push_chunk(chunk);
+                for fragment in fragments {
+                    yield fragment;
+                }
+            }
+            Self::record_response(history, usage, &assembled)?;
+        })
+    }
+
+    /// Sends a message along with functions, that the model may call in addition to the ones from the message config
+    pub async fn send_message_with_functions(
+        &mut self,
+        message: Message,
+        functions: Vec<FunctionDefinition>,
+    ) -> Result<Message> {
+        self.push_message(message).await?;
+
+        let resp = self
+            .client
+            .with_functions(functions)
+            .send_completion(self.message_history.clone(), self.cache_uuid())
+            .await?;
+        self.record(&resp)
+    }
+
+    /// Registers a function, that is executed by `run` whenever the model calls it
+    pub fn register_function<F, Fut, E>(&mut self, definition: FunctionDefinition, handler: F)
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<Value, E>> + Send + 'static,
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let handler: FunctionHandler =
+            Box::new(move |args| handler(args).map(|res| res.map_err(Into::into)).boxed());
+        self.functions
+            .insert(definition.name.clone(), (definition, handler));
+    }
+
+    /// Registers a function, which parameters are described by a type deriving `JsonSchema`
+    ///
+    /// Arguments are deserialized into the type before calling the handler, `GigaError::InvalidArguments` is returned by `run` if they don't match
+    #[cfg(feature = "derive")]
+    pub fn register_typed_function<T, F, Fut, E>(&mut self, name: &str, handler: F)
+    where
+        T: schemars::JsonSchema + serde::de::DeserializeOwned + Send + 'static,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<Value, E>> + Send + 'static,
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let definition = FunctionDefinition::from_type::<T>(name);
+        let name = name.to_owned();
+        self.register_function(definition, move |args: Value| {
+            let call = crate::http::function::FunctionCall {
+                name: name.clone(),
+                arguments: args,
+            };
+            let fut = call.parse_arguments::<T>().map(&handler);
+            async move {
+                match fut {
+                    Ok(fut) => fut.await.map_err(Into::into),
+                    Err(err) => Err(Box::new(err) as Box<dyn Error + Send + Sync>),
+                }
+            }
+        });
+    }
+
+    /// Sets how many function calls `run` makes before giving up, 5 by default, at least 1
+    pub fn set_max_steps(&mut self, max_steps: usize) {
+        self.max_steps = max_steps.max(1);
+    }
+
+    /// Sends a message and executes the functions the model calls, until the model gives a normal answer
+    ///
+    /// Function calls and their results are stored in the message history, if the run fails the history is left as it was before
+    pub async fn run(&mut self, message: Message) -> Result<Message> {
+        let start = self.message_history.len();
+        self.push_message(message).await?;
+
+        let result = self.run_steps().await;
+        if result.is_err() {
+            // A function call without a result would break every next request of the chat
+            self.message_history.truncate(start);
+        }
+        result
+    }
+
+    /// Calls functions until the model answers, the message is expected to be in the history already
+    async fn run_steps(&mut self) -> Result<Message> {
+        let client = self.client.with_functions(
+            self.functions
+                .values()
+                .map(|(definition, _)| definition.clone())
+                .collect(),
+        );
+        for step in 0..=self.max_steps {
+            let resp = client
+                .send_completion(self.message_history.clone(), self.cache_uuid())
+                .await?;
+            let resp = self.record(&resp)?;
+
+            let Some(call) = resp.function_call else {
+                return Ok(resp);
+            };
+            // The result of the call would never be sent, so the function is not executed at all
+            if step == self.max_steps {
+                break;
+            }
+            let (_, handler) = self
+                .functions
+                .get(&call.name)
+                .ok_or_else(|| GigaError::UnknownFunction(call.name.clone()))?;
+            let result = handler(call.arguments).await.map_err(|source| {
+                // Errors of the library itself, e.g. invalid arguments, are returned as is
+                match source.downcast::<GigaError>() {
+                    Ok(err) => *err,
+                    Err(source) => GigaError::FunctionFailed {
+                        name: call.name.clone(),
+                        source,
+                    },
+                }
+            })?;
+
+            self.message_history
+                .push(Message::function_result(&call.name, &result));
+        }
+
+        Err(GigaError::MaxStepsExceeded(self.max_steps))
+    }
+
+    /// Counts tokens of the whole message history, using the model from the client config
+    pub async fn history_token_count(&self) -> Result<u32> {
+        if self.message_history.is_empty() {
+            return Ok(0);
+        }
+
+        let counts = self
+            .client
+            .count_tokens(
+                &self.client.get_current_config().model,
+                self.message_history
+                    .iter()
+                    .map(|message| message.content.clone())
+                    .collect(),
+            )
+            .await?;
+        Ok(counts.iter().map(|count| count.tokens).sum())
+    }
+
+    /// Returns the amount of tokens spent by the chat so far
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    /// Stores a new message in the history, once its attachments are checked
+    ///
+    /// A rejected message never gets into the history, otherwise it would be sent with every next request
+    async fn push_message(&mut self, message: Message) -> Result<()> {
+        self.client.check_attachments(&message).await?;
+        self.message_history.push(message);
+        Ok(())
+    }
+
+    /// Adds the response usage to the total and stores the answer in the message history
+    fn record(&mut self, resp: &ChatResponse) -> Result<Message> {
+        Self::record_response(&mut self.message_history, &mut self.usage, resp)
+    }
+
+    /// Same as `record`, for the places where the client is borrowed at the same time
+    fn record_response(
+        history: &mut Vec<Message>,
+        usage: &mut Usage,
+        resp: &ChatResponse,
+    ) -> Result<Message> {
+        let answer = resp.answer()?;
+        *usage += resp.usage.clone();
+        history.push(answer.clone());
+        Ok(answer)
+    }
+
+    /// Session ID, which makes the API cache the history, if the chat is cached
+    fn cache_uuid(&self) -> Option<&str> {
+        if self.cache_uuid.is_empty() {
+            None
+        } else {
+            Some(&self.cache_uuid)
+        }
+    }
+
     // Returns a reference to the message history, allowing read-only access
     pub fn get_message_history(&self) -> &Vec<Message> {
         &self.message_history
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        controllers::{client::ClientBuilder, credentials::StaticToken},
+        error::GigaError,
+        http::message::Message,
+    };
+
+    use super::Chat;
+
+    #[tokio::test]
+    async fn rejected_message_is_not_stored() {
+        let client = ClientBuilder::new()
+            .set_credential_provider(StaticToken::new("token"))
+            .build()
+            .unwrap();
+        let mut chat = Chat::new(client);
+
+        // Too many attachments are rejected before any request is sent
+        let message = Message::from_str("Hi").with_attachments(&["file"; 11]);
+        let err = chat.send_message(message).await.unwrap_err();
+        assert!(matches!(err, GigaError::InvalidInput(_)));
+        assert!(chat.get_message_history().is_empty());
+
+        let message = Message::from_str("Hi").with_attachments(&["file"; 11]);
+        assert!(chat.run(message).await.is_err());
+        assert!(chat.get_message_history().is_empty());
+    }
+}
@@ -1,232 +1,423 @@
 use std::{
-    collections::HashMap,
+    future::Future,
     path::PathBuf,
-    sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::anyhow;
+use futures::{future::ready, Stream, StreamExt};
 use reqwest::{
-    header::{HeaderMap, HeaderValue, ACCEPT},
+    header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE},
     multipart::{Form, Part},
+    Certificate, Identity, StatusCode,
 };
-
-use crate::http::{
-    message::{Message, MessageConfig},
-    request::ChatRequest,
-    response::{ChatResponse, Model},
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    error::{GigaError, Result},
+    http::{
+        function::FunctionDefinition,
+        message::{Message, MessageConfig},
+        request::{ChatRequest, EmbeddingsRequest, TokensCountRequest},
+        response::{
+            Balance, BalanceList, ChatResponse, ChatStreamChunk, DataList, Embeddings, Model,
+            TokensCount,
+        },
+    },
 };
 
-use super::{access_token::AccessToken, file::GigaFile, httpclient::HttpClient};
+use super::{
+    access_token::{AccessToken, Scope},
+    credentials::{encode_basic, AuthContext, CredentialProvider, OAuthCredentials},
+    file::{check_upload, mime_from_filename, GigaFile, DEFAULT_FILE_PURPOSE},
+    httpclient::{ClientConfig, HttpClient, RequestOptions},
+    rate_limit::{estimate_tokens, RateLimit, RateLimiter, RatePermit},
+    retry::RetryPolicy,
+};
 
 const BASE_URL_AUTH: &str = "https://ngw.devices.sberbank.ru:9443/api";
 const BASE_URL: &str = "https://gigachat.devices.sberbank.ru/api";
+/// How many inputs are sent in a single embeddings request
+const EMBEDDINGS_BATCH_SIZE: usize = 100;
+/// How long before expiration the access token gets refreshed by default
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+/// How many files can be attached to a single message
+const MAX_ATTACHMENTS: usize = 10;
+/// How many of the attached files can be images
+const MAX_IMAGE_ATTACHMENTS: usize = 1;
 
 /// The main thing, which interacts with the GigaChat API
+///
+/// Cloning is cheap, all clones share the same access token
 #[derive(Clone)]
 pub struct GigaClient {
     // Tokens
-    basic_token: String,
-    auth_token: Arc<Option<AccessToken>>,
+    credentials: Option<Arc<dyn CredentialProvider>>,
+    auth_token: Arc<Mutex<Option<AccessToken>>>,
+    token_refresh_margin: Duration,
+
+    // Endpoints
+    base_url: String,
+    auth_url: String,
 
     // Settings for messages
     message_cfg: MessageConfig,
-    uuid: String,
+    file_purpose: String,
 
     // Other
     httpclient: HttpClient,
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
-// impl Clone for GigaClient {
-//     fn clone(&self) -> Self {
-//         Self {
-//             basic_token: self.basic_token.clone(),
-//             auth_token: Arc::new(Mutex::new),
-//             message_cfg: (),
-//             uuid: (),
-//             httpclient: (),
-//         }
-//     }
-// }
-
 impl GigaClient {
-    pub async fn send_message(&mut self, message: Message) -> anyhow::Result<Message> {
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.append(
-            "Content-Type",
-            reqwest::header::HeaderValue::from_str("application/json").unwrap(),
-        );
-        headers.append(
-            "Accept",
-            reqwest::header::HeaderValue::from_str("application/json").unwrap(),
-        );
-        headers.append(
-            "Authorization",
-            reqwest::header::HeaderValue::from_str(
-                format!(
-                    "Bearer {}",
-                    self.get_auth_token().await.unwrap().access_token
-                )
-                .as_str(),
-            )
-            .unwrap(),
-        );
-
-        let json_msg = ChatRequest {
-            model: self.message_cfg.model.clone(),
-            messages: vec![message],
-            temperature: self.message_cfg.temperature,
-            top_p: self.message_cfg.top_p,
-            stream: self.message_cfg.stream,
-            max_tokens: self.message_cfg.max_tokens,
-            repetition_penalty: self.message_cfg.repetition_penalty,
-        };
+    pub async fn send_message(&self, message: Message) -> Result<Message> {
+        self.send_messages(vec![message], None).await
+    }
 
-        let resp: ChatResponse = self
-            .httpclient
-            .post_data(
-                &(BASE_URL.to_owned() + "/v1/chat/completions"),
-                serde_json::to_string(&json_msg).unwrap(),
-                headers,
-            )
-            .await?;
+    /// Sends a message and returns the whole response, including token usage and the finish reason
+    pub async fn send_message_full(&self, message: Message) -> Result<ChatResponse> {
+        self.complete(vec![message]).await
+    }
+
+    /// Sends several messages as one conversation and returns the whole response
+    pub async fn complete(&self, messages: Vec<Message>) -> Result<ChatResponse> {
+        self.complete_messages(messages, None).await
+    }
+
+    /// Sends a message with streaming enabled, yielding message fragments as soon as they arrive
+    pub async fn send_message_stream(
+        &self,
+        message: Message,
+    ) -> Result<impl Stream<Item = Result<Message>>> {
+        self.send_messages_stream(vec![message], None).await
+    }
+
+    /// Sends a message along with functions, that the model may call in addition to the ones from the message config
+    ///
+    /// If the model decides to call a function, it is returned in `Message::function_call`
+    pub async fn send_message_with_functions(
+        &self,
+        message: Message,
+        functions: Vec<FunctionDefinition>,
+    ) -> Result<Message> {
+        self.with_functions(functions)
+            .send_messages(vec![message], None)
+            .await
+    }
+
+    /// Returns a copy of the client, which uses the options instead of the ones set in the builder, the token and the rate limit are shared
+    pub fn with_options(&self, options: RequestOptions) -> GigaClient {
+        let mut client = self.clone();
+        client.httpclient = self.httpclient.with_options(&options);
+        client
+    }
 
-        Ok(resp
-            .choices
-            .last()
-            .ok_or_else(|| anyhow!("There is no Choice from the AI"))?
-            .message
-            .clone())
+    /// Returns a copy of the client, which sends extra functions with every request
+    pub(crate) fn with_functions(&self, functions: Vec<FunctionDefinition>) -> GigaClient {
+        let mut client = self.clone();
+        client.message_cfg.functions.extend(functions);
+        client
     }
 
     /// Non-pub function used for sending multiple messages, primarily used by 'Chat'
     pub(crate) async fn send_messages(
-        &mut self,
+        &self,
         messages: Vec<Message>,
         cache_uuid: Option<&str>,
-    ) -> anyhow::Result<Message> {
-        let mut headers = HeaderMap::new();
-        headers.append(
-            "Content-Type",
-            HeaderValue::from_str("application/json").unwrap(),
-        );
-        headers.append("Accept", HeaderValue::from_str("application/json").unwrap());
+    ) -> Result<Message> {
+        self.complete_messages(messages, cache_uuid).await?.answer()
+    }
+
+    /// Same as 'send_messages', but returns the whole response
+    pub(crate) async fn complete_messages(
+        &self,
+        messages: Vec<Message>,
+        cache_uuid: Option<&str>,
+    ) -> Result<ChatResponse> {
+        if let Some(message) = messages.last() {
+            self.check_attachments(message).await?;
+        }
+        self.send_completion(messages, cache_uuid).await
+    }
+
+    /// Same as 'complete_messages', but the attachments are expected to be checked already
+    pub(crate) async fn send_completion(
+        &self,
+        messages: Vec<Message>,
+        cache_uuid: Option<&str>,
+    ) -> Result<ChatResponse> {
+        // A streamed response can't be parsed as a single ChatResponse, so it is assembled from chunks instead
+        if self.message_cfg.stream.unwrap_or(false) {
+            let chunks = self.stream_completion(messages, cache_uuid).await?;
+            futures::pin_mut!(chunks);
+
+            let mut assembled = ChatResponse::empty(&self.message_cfg.model);
+            while let Some(chunk) = chunks.next().await {
+                assembled.push_chunk(chunk?);
+            }
+            return Ok(assembled);
+        }
+        let _permit = self.rate_limit(&messages).await;
+
+        let json_msg = serde_json::to_string(&self.chat_request(messages, false))?;
+        let api_url = self.base_url.to_owned() + "/v1/chat/completions";
+
+        self.with_auth(|headers| {
+            let (api_url, json_msg) = (&api_url, &json_msg);
+            async move {
+                let headers = Self::chat_headers(cache_uuid, headers)?;
+                self.httpclient
+                    .post_data(api_url, json_msg.clone(), headers)
+                    .await
+            }
+        })
+        .await
+    }
+
+    /// Non-pub function used for streaming a response to multiple messages, primarily used by 'Chat'
+    pub(crate) async fn send_messages_stream(
+        &self,
+        messages: Vec<Message>,
+        cache_uuid: Option<&str>,
+    ) -> Result<impl Stream<Item = Result<Message>>> {
+        let chunks = self.send_chunks_stream(messages, cache_uuid).await?;
+
+        Ok(chunks.flat_map(|chunk| {
+            let fragments: Vec<Result<Message>> = match chunk {
+                Ok(chunk) => chunk
+                    .choices
+                    .into_iter()
+                    .map(|choice| Ok(choice.delta.into()))
+                    .collect(),
+                Err(why) => vec![Err(why)],
+            };
+            futures::stream::iter(fragments)
+        }))
+    }
+
+    /// Streams the raw chunks of a response, which also carry the finish reason and usage
+    pub(crate) async fn send_chunks_stream(
+        &self,
+        messages: Vec<Message>,
+        cache_uuid: Option<&str>,
+    ) -> Result<impl Stream<Item = Result<ChatStreamChunk>>> {
+        if let Some(message) = messages.last() {
+            self.check_attachments(message).await?;
+        }
+        self.stream_completion(messages, cache_uuid).await
+    }
+
+    /// Same as 'send_chunks_stream', but the attachments are expected to be checked already
+    pub(crate) async fn stream_completion(
+        &self,
+        messages: Vec<Message>,
+        cache_uuid: Option<&str>,
+    ) -> Result<impl Stream<Item = Result<ChatStreamChunk>>> {
+        let permit = self.rate_limit(&messages).await;
+        let json_msg = serde_json::to_string(&self.chat_request(messages, true))?;
+        let api_url = self.base_url.to_owned() + "/v1/chat/completions";
+
+        let chunks = self
+            .with_auth(|headers| {
+                let (api_url, json_msg) = (&api_url, &json_msg);
+                async move {
+                    let mut headers = Self::chat_headers(cache_uuid, headers)?;
+                    headers.insert(ACCEPT, HeaderValue::from_static("text/event-stream"));
+                    self.httpclient
+                        .post_stream::<_, ChatStreamChunk>(api_url, json_msg.clone(), headers)
+                        .await
+                }
+            })
+            .await?;
+
+        // The stream keeps its concurrency slot until it is dropped
+        Ok(chunks.map(move |chunk| {
+            let _ = &permit;
+            chunk
+        }))
+    }
+
+    /// Waits for the turn of a chat request, if the rate limit is set
+    async fn rate_limit(&self, messages: &[Message]) -> Option<RatePermit> {
+        let limiter = self.rate_limiter.as_ref()?;
+        let tokens = estimate_tokens(messages, self.message_cfg.max_tokens);
+        Some(limiter.acquire(tokens).await)
+    }
+
+    /// Returns how many chat requests are waiting for the rate limit, 0 if it is not set
+    ///
+    /// The queue is shared by all clones of the client
+    pub fn queue_depth(&self) -> usize {
+        self.rate_limiter
+            .as_ref()
+            .map_or(0, |limiter| limiter.queue_depth())
+    }
+
+    /// Headers used by the chat completion requests
+    fn chat_headers(cache_uuid: Option<&str>, mut headers: HeaderMap) -> Result<HeaderMap> {
+        headers.append(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.append(ACCEPT, HeaderValue::from_static("application/json"));
         if let Some(cache_str) = cache_uuid {
-            headers.append("X-Session-ID", HeaderValue::from_str(cache_str).unwrap());
+            headers.append("X-Session-ID", HeaderValue::from_str(cache_str)?);
         }
-        headers.append(
-            "Authorization",
-            reqwest::header::HeaderValue::from_str(
-                format!(
-                    "Bearer {}",
-                    self.get_auth_token().await.unwrap().access_token
-                )
-                .as_str(),
-            )
-            .unwrap(),
-        );
-
-        let json_msg = ChatRequest {
+        Ok(headers)
+    }
+
+    /// Builds a chat completion request body out of the current message config
+    fn chat_request(&self, messages: Vec<Message>, stream: bool) -> ChatRequest {
+        ChatRequest {
             model: self.message_cfg.model.clone(),
             messages,
             temperature: self.message_cfg.temperature,
             top_p: self.message_cfg.top_p,
-            stream: self.message_cfg.stream,
+            stream: stream.then_some(true),
             max_tokens: self.message_cfg.max_tokens,
             repetition_penalty: self.message_cfg.repetition_penalty,
-        };
+            n: self.message_cfg.n,
+            update_interval: self.message_cfg.update_interval,
+            profanity_check: self.message_cfg.profanity_check,
+            functions: self.message_cfg.functions.clone(),
+            function_call: self.message_cfg.function_call.clone(),
+        }
+    }
 
-        let resp: ChatResponse = self
-            .httpclient
-            .post_data(
-                &(BASE_URL.to_owned() + "/v1/chat/completions"),
-                serde_json::to_string(&json_msg).unwrap(),
-                headers,
-            )
+    /// Returns available GigaChat AI models
+    pub async fn get_models(&self) -> Result<Vec<Model>> {
+        let api_url = self.base_url.to_owned() + "/v1/models";
+
+        let resp: DataList<Model> = self
+            .with_auth(|mut headers| {
+                let api_url = &api_url;
+                async move {
+                    headers.append(ACCEPT, HeaderValue::from_static("application/json"));
+                    self.httpclient.get(api_url, headers).await
+                }
+            })
             .await?;
 
-        Ok(resp
-            .choices
-            .last()
-            .ok_or_else(|| anyhow!("There is no choice from the AI"))?
-            .message
-            .clone())
+        Ok(resp.data)
     }
 
-    /// Returns available GigaChat AI models
-    pub async fn get_models(&mut self) -> anyhow::Result<Vec<Model>> {
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.append("Accept", HeaderValue::from_str("application/json").unwrap());
-        headers.append(
-            "Authorization",
-            HeaderValue::from_str(
-                format!(
-                    "Bearer {}",
-                    self.get_auth_token().await.unwrap().access_token
-                )
-                .as_str(),
-            )
-            .unwrap(),
-        );
-
-        let resp: serde_json::Value = self
-            .httpclient
-            .get(&(BASE_URL.to_owned() + "/v1/models"), headers)
+    /// Returns the remaining token quota of the account per model
+    ///
+    /// Only available for prepaid accounts, otherwise the API returns an error
+    pub async fn get_balance(&self) -> Result<Vec<Balance>> {
+        let api_url = self.base_url.to_owned() + "/v1/balance";
+
+        let resp: BalanceList = self
+            .with_auth(|mut headers| {
+                let api_url = &api_url;
+                async move {
+                    headers.append(ACCEPT, HeaderValue::from_static("application/json"));
+                    self.httpclient.get(api_url, headers).await
+                }
+            })
             .await?;
 
-        let mdls: Vec<Model> = resp
-            .get("data")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|val| serde_json::from_value(val.clone()).unwrap())
-            .collect();
+        Ok(resp.balance)
+    }
+
+    /// Returns embeddings of the inputs, made by an embedding model, e.g. `Embeddings`
+    ///
+    /// Inputs are split into several requests, if there are too many of them for one
+    pub async fn embeddings(&self, model: &str, inputs: Vec<String>) -> Result<Embeddings> {
+        let api_url = self.base_url.to_owned() + "/v1/embeddings";
 
-        Ok(mdls)
+        let mut embeddings = Embeddings {
+            object: "list".to_owned(),
+            data: Vec::with_capacity(inputs.len()),
+            model: model.to_owned(),
+        };
+        for (batch_idx, batch) in inputs.chunks(EMBEDDINGS_BATCH_SIZE).enumerate() {
+            let json_req = serde_json::to_string(&EmbeddingsRequest {
+                model: model.to_owned(),
+                input: batch.to_vec(),
+            })?;
+
+            let resp: Embeddings = self
+                .with_auth(|mut headers| {
+                    let (api_url, json_req) = (&api_url, &json_req);
+                    async move {
+                        headers.append(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                        headers.append(ACCEPT, HeaderValue::from_static("application/json"));
+                        self.httpclient
+                            .post_data(api_url, json_req.clone(), headers)
+                            .await
+                    }
+                })
+                .await?;
+
+            // Indexes are relative to the batch, so they are shifted to match the inputs
+            let offset = (batch_idx * EMBEDDINGS_BATCH_SIZE) as u32;
+            embeddings.model = resp.model;
+            embeddings
+                .data
+                .extend(resp.data.into_iter().map(|mut embedding| {
+                    embedding.index += offset;
+                    embedding
+                }));
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Counts tokens of every input the way the model would, useful to check whether a prompt fits the context
+    pub async fn count_tokens(&self, model: &str, inputs: Vec<String>) -> Result<Vec<TokensCount>> {
+        let api_url = self.base_url.to_owned() + "/v1/tokens/count";
+        let json_req = serde_json::to_string(&TokensCountRequest {
+            model: model.to_owned(),
+            input: inputs,
+        })?;
+
+        self.with_auth(|mut headers| {
+            let (api_url, json_req) = (&api_url, &json_req);
+            async move {
+                headers.append(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                headers.append(ACCEPT, HeaderValue::from_static("application/json"));
+                self.httpclient
+                    .post_data(api_url, json_req.clone(), headers)
+                    .await
+            }
+        })
+        .await
     }
 
     /// Returns file information, which includes timestamps, filename, id and etc...
-    pub async fn get_file_info(&mut self, file_id: &str) -> anyhow::Result<GigaFile> {
-        let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT, HeaderValue::from_str("application/json").unwrap());
-        headers.append(
-            "Authorization",
-            HeaderValue::from_str(
-                format!(
-                    "Bearer {}",
-                    self.get_auth_token().await.unwrap().access_token
-                )
-                .as_str(),
-            )
-            .unwrap(),
-        );
-        let api_url = BASE_URL.to_owned() + &format!("/v1/files/{}", file_id);
-        let resp: GigaFile = self.httpclient.get(&api_url, headers).await?;
+    pub async fn get_file_info(&self, file_id: &str) -> Result<GigaFile> {
+        let api_url = self.base_url.to_owned() + &format!("/v1/files/{}", file_id);
+
+        let resp: GigaFile = self
+            .with_auth(|mut headers| {
+                let api_url = &api_url;
+                async move {
+                    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+                    self.httpclient.get(api_url, headers).await
+                }
+            })
+            .await?;
 
         Ok(resp)
     }
 
     /// Gets a list of available files, that user have uploaded before
-    pub async fn get_files(&mut self) -> anyhow::Result<Vec<GigaFile>> {
-        let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT, HeaderValue::from_str("application/json").unwrap());
-        headers.append(
-            "Authorization",
-            HeaderValue::from_str(
-                format!(
-                    "Bearer {}",
-                    self.get_auth_token().await.unwrap().access_token
-                )
-                .as_str(),
-            )
-            .unwrap(),
-        );
-        let api_url = BASE_URL.to_owned() + "/v1/files";
-        let mut files: HashMap<String, Vec<GigaFile>> =
-            self.httpclient.get(&api_url, headers).await?;
-
-        Ok(files.remove_entry("data").unwrap().1)
+    pub async fn get_files(&self) -> Result<Vec<GigaFile>> {
+        let api_url = self.base_url.to_owned() + "/v1/files";
+
+        let files: DataList<GigaFile> = self
+            .with_auth(|mut headers| {
+                let api_url = &api_url;
+                async move {
+                    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+                    self.httpclient.get(api_url, headers).await
+                }
+            })
+            .await?;
+
+        Ok(files.data)
     }
 
     /// Sets to default if new_cfg is None, otherwise set to the passed config
@@ -237,128 +428,330 @@ impl GigaClient {
         self.message_cfg.clone()
     }
 
-    /// Gets an OAuth config, needed for requests to the API
-    async fn get_auth_token(&mut self) -> anyhow::Result<AccessToken> {
-        if SystemTime::now()
+    /// Gets an access token from the credential provider, needed for requests to the API
+    async fn get_auth_token(&self) -> Result<Option<AccessToken>> {
+        let Some(credentials) = self.credentials.as_ref() else {
+            // Authenticated by the client certificate
+            return Ok(None);
+        };
+
+        // The lock is held during the refresh, so concurrent requests wait for a single token request
+        let mut auth_token = self.auth_token.lock().await;
+
+        // GigaChat sends expires_at in milliseconds
+        let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            > self
-                .auth_token
-                .as_ref()
-                .clone()
-                .map_or(0, |tok| tok.expires_at)
+            .unwrap_or_default()
+            .as_millis();
+        if let Some(tok) = auth_token.as_ref() {
+            if now + self.token_refresh_margin.as_millis() < u128::from(tok.expires_at) {
+                return Ok(Some(tok.clone()));
+            }
+        }
+
+        let tok = credentials
+            .fetch_token(AuthContext {
+                httpclient: &self.httpclient,
+                base_url: &self.base_url,
+                auth_url: &self.auth_url,
+            })
+            .await?;
+
+        *auth_token = Some(tok.clone());
+        Ok(Some(tok))
+    }
+
+    /// Drops the cached access token, unless another request has already replaced it
+    async fn invalidate_auth_token(&self, stale: &AccessToken) {
+        let mut auth_token = self.auth_token.lock().await;
+        if auth_token
+            .as_ref()
+            .is_some_and(|tok| tok.access_token == stale.access_token)
         {
-            let mut headers: reqwest::header::HeaderMap = reqwest::header::HeaderMap::new();
-            headers.append(
-                "Content-Type",
-                reqwest::header::HeaderValue::from_str("application/x-www-form-urlencoded")
-                    .unwrap(),
-            );
-            headers.append(
-                "Accept",
-                reqwest::header::HeaderValue::from_str("application/json").unwrap(),
-            );
-            headers.append(
-                "RqUID",
-                reqwest::header::HeaderValue::from_str(&self.uuid).unwrap(),
-            );
+            *auth_token = None;
+        }
+    }
+
+    /// Runs a request with the `Authorization` header, retrying it once with a fresh token if the API responds with 401
+    async fn with_auth<R, F, Fut>(&self, request: F) -> Result<R>
+    where
+        F: Fn(HeaderMap) -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        let token = self.get_auth_token().await?;
+        match request(Self::auth_headers(token.as_ref())?).await {
+            Err(GigaError::Unauthorized(err))
+                if err.http_status == StatusCode::UNAUTHORIZED && token.is_some() =>
+            {
+                if let Some(token) = token.as_ref() {
+                    self.invalidate_auth_token(token).await;
+                }
+                let token = self.get_auth_token().await?;
+                request(Self::auth_headers(token.as_ref())?).await
+            }
+            resp => resp,
+        }
+    }
+
+    /// Builds the `Authorization` header out of an access token, if there is one
+    fn auth_headers(token: Option<&AccessToken>) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = token {
             headers.append(
-                "Authorization",
-                reqwest::header::HeaderValue::from_str(
-                    ("Basic ".to_owned() + &self.basic_token).as_str(),
-                )
-                .unwrap(),
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", token.access_token))?,
             );
-            let mut form_data: HashMap<String, String> = HashMap::new();
-            form_data.insert("scope".to_owned(), "GIGACHAT_API_PERS".to_owned());
-
-            let tok: AccessToken = self
-                .httpclient
-                .post_form(
-                    &(BASE_URL_AUTH.to_owned() + "/v2/oauth"),
-                    form_data,
-                    headers,
-                )
-                .await
-                .expect("Fatal error: Could not get auth token");
-
-            self.auth_token = Arc::new(Some(tok));
         }
-
-        Ok(self.auth_token.as_ref().clone().unwrap())
+        Ok(headers)
     }
 
     // Files
     /// Uploads a file to the GigaChat storage
-    pub async fn upload_file(&mut self, filepath: PathBuf) -> anyhow::Result<GigaFile> {
-        let file = tokio::fs::read(&filepath).await?;
-
-        let mime_type = tree_magic::from_filepath(&filepath);
-        let filename = filepath.file_name().unwrap().to_str().unwrap().to_owned();
-
-        let form = Form::new()
-            .part(
-                "file",
-                Part::bytes(file)
-                    .file_name(filename)
-                    .mime_str(&mime_type)
-                    .unwrap(),
-            )
-            .text("purpose", "general");
+    ///
+    /// The file is streamed, so it is never fully loaded into memory, it is opened again if the upload is retried
+    pub async fn upload_file(&self, filepath: PathBuf) -> Result<GigaFile> {
+        let filename = filepath
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| GigaError::InvalidInput(format!("Invalid file path: {:?}", filepath)))?
+            .to_owned();
+        // Office documents are zip archives inside, so the extension says more than the contents
+        let mime_type = match mime_from_filename(&filename) {
+            Some(mime_type) => mime_type.to_owned(),
+            None => tree_magic::from_filepath(&filepath),
+        };
 
-        let mut headers = HeaderMap::new();
-        headers.append(
-            "Authorization",
-            HeaderValue::from_str(&format!(
-                "Bearer {}",
-                self.get_auth_token().await.unwrap().access_token
-            ))
-            .unwrap(),
-        );
-
-        let file: GigaFile = self
-            .httpclient
-            .post_multipart(&(BASE_URL.to_owned() + "/v1/files"), form, headers)
+        check_upload(&mime_type, tokio::fs::metadata(&filepath).await?.len())?;
+        let api_url = self.base_url.to_owned() + "/v1/files";
+
+        self.with_auth(|headers| {
+            let (api_url, filepath, filename, mime_type) =
+                (&api_url, &filepath, &filename, &mime_type);
+            async move {
+                let form = move || async move {
+                    let file = tokio::fs::File::open(filepath).await?;
+                    let len = file.metadata().await?.len();
+                    let body = reqwest::Body::wrap_stream(ReaderStream::new(file));
+                    self.file_form(Part::stream_with_length(body, len), filename, mime_type)
+                };
+
+                self.httpclient.post_multipart(api_url, form, headers).await
+            }
+        })
+        .await
+    }
+
+    /// Uploads a file, which is already in memory, e.g. an image received from another service
+    pub async fn upload_bytes(&self, name: &str, bytes: Vec<u8>, mime: &str) -> Result<GigaFile> {
+        check_upload(mime, bytes.len() as u64)?;
+        let api_url = self.base_url.to_owned() + "/v1/files";
+
+        self.with_auth(|headers| {
+            let (api_url, bytes) = (&api_url, &bytes);
+            async move {
+                let form = move || ready(self.file_form(Part::bytes(bytes.clone()), name, mime));
+
+                self.httpclient.post_multipart(api_url, form, headers).await
+            }
+        })
+        .await
+    }
+
+    /// Uploads a file by streaming it from the reader, `len` has to be the exact amount of bytes the reader gives
+    ///
+    /// The reader can't be rewound, so unlike other requests the upload is sent only once: neither a rejected token nor the retry policy makes it retried
+    pub async fn upload_reader<R>(
+        &self,
+        name: &str,
+        reader: R,
+        len: u64,
+        mime: &str,
+    ) -> Result<GigaFile>
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        check_upload(mime, len)?;
+        let api_url = self.base_url.to_owned() + "/v1/files";
+
+        let token = self.get_auth_token().await?;
+        let headers = Self::auth_headers(token.as_ref())?;
+        let body = reqwest::Body::wrap_stream(ReaderStream::new(reader));
+        let form = self.file_form(Part::stream_with_length(body, len), name, mime)?;
+
+        self.httpclient
+            .post_multipart_once(&api_url, form, headers)
+            .await
+    }
+
+    /// Builds the upload form out of the file contents
+    fn file_form(&self, contents: Part, name: &str, mime: &str) -> Result<Form> {
+        Ok(Form::new()
+            .part("file", contents.file_name(name.to_owned()).mime_str(mime)?)
+            .text("purpose", self.file_purpose.clone()))
+    }
+
+    /// Downloads contents of a file from the storage, e.g. an image generated by the model
+    pub async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        let api_url = self.base_url.to_owned() + &format!("/v1/files/{}/content", file_id);
+
+        self.with_auth(|mut headers| {
+            let api_url = &api_url;
+            async move {
+                headers.append(ACCEPT, HeaderValue::from_static("*/*"));
+                self.httpclient.get_bytes(api_url, headers).await
+            }
+        })
+        .await
+    }
+
+    /// Downloads contents of a file straight into the writer, without keeping the whole file in memory
+    ///
+    /// Returns the amount of bytes written
+    pub async fn download_file_to<W>(&self, file_id: &str, writer: &mut W) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let api_url = self.base_url.to_owned() + &format!("/v1/files/{}/content", file_id);
+
+        let chunks = self
+            .with_auth(|mut headers| {
+                let api_url = &api_url;
+                async move {
+                    headers.append(ACCEPT, HeaderValue::from_static("*/*"));
+                    self.httpclient.get_stream(api_url, headers).await
+                }
+            })
             .await?;
+        futures::pin_mut!(chunks);
+
+        let mut written = 0;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            writer.write_all(chunk.as_ref()).await?;
+            written += chunk.as_ref().len() as u64;
+        }
+        writer.flush().await?;
 
-        Ok(file)
+        Ok(written)
     }
 
-    /// Deletes a file from the storage
-    pub async fn delete_file(&mut self, file_id: &str) -> anyhow::Result<()> {
-        let api_url = format!(
-            "https://gigachat.devices.sberbank.ru/api/v1/files/{}/delete",
-            file_id
-        );
+    /// Downloads every image the message refers to, returning their IDs along with contents
+    pub async fn download_images(&self, message: &Message) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut images = Vec::new();
+        for id in message.image_ids() {
+            let bytes = self.download_file(&id).await?;
+            images.push((id, bytes));
+        }
+        Ok(images)
+    }
 
-        let mut headers = HeaderMap::new();
-        headers.append("Accept", HeaderValue::from_str("application/json").unwrap());
-        headers.append(
-            "Authorization",
-            HeaderValue::from_str(&format!(
-                "Bearer {}",
-                self.get_auth_token().await.unwrap().access_token
-            ))
-            .unwrap(),
-        );
+    /// Checks that the API will accept the files attached to the message
+    ///
+    /// Fails with `GigaError::InvalidInput` if there are too many attachments, some of them are deleted or can't be attached
+    ///
+    /// Makes a file info request per attachment, these requests are not counted by the rate limit.
+    /// The check runs automatically before sending a message, unless `MessageConfig::validate_attachments` is off
+    pub async fn validate_attachments(&self, message: &Message) -> Result<()> {
+        let attachments = message.attachments();
+        if attachments.len() > MAX_ATTACHMENTS {
+            return Err(GigaError::InvalidInput(format!(
+                "A message can have at most {} attachments, got {}",
+                MAX_ATTACHMENTS,
+                attachments.len()
+            )));
+        }
+
+        let mut images = 0;
+        for id in attachments {
+            let file = match self.get_file_info(id).await {
+                Err(GigaError::NotFound(_)) => {
+                    return Err(GigaError::InvalidInput(format!(
+                        "Attached file {} does not exist",
+                        id
+                    )))
+                }
+                file => file?,
+            };
+            if !file.is_attachable() {
+                return Err(GigaError::InvalidInput(format!(
+                    "File {} has purpose {} and can't be attached",
+                    id, file.purpose
+                )));
+            }
+            if file.is_image() {
+                images += 1;
+            }
+        }
+        if images > MAX_IMAGE_ATTACHMENTS {
+            return Err(GigaError::InvalidInput(format!(
+                "A message can have at most {} image attachments, got {}",
+                MAX_IMAGE_ATTACHMENTS, images
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Validates attachments of a new message, unless it is turned off in the config
+    ///
+    /// Has to be called before the message gets into a history, which is never validated again
+    pub(crate) async fn check_attachments(&self, message: &Message) -> Result<()> {
+        if self.message_cfg.validate_attachments && !message.attachments().is_empty() {
+            self.validate_attachments(message).await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Deletes a file from the storage
+    pub async fn delete_file(&self, file_id: &str) -> Result<()> {
+        let api_url = self.base_url.to_owned() + &format!("/v1/files/{}/delete", file_id);
 
         let json_obj: serde_json::Value = self
-            .httpclient
-            .post_data(&api_url, String::new(), headers)
+            .with_auth(|mut headers| {
+                let api_url = &api_url;
+                async move {
+                    headers.append(ACCEPT, HeaderValue::from_static("application/json"));
+                    self.httpclient
+                        .post_data(api_url, String::new(), headers)
+                        .await
+                }
+            })
             .await?;
 
-        if !json_obj.get("deleted").unwrap().as_bool().unwrap_or(false) {
-            return Err(anyhow!("File was not deleted"));
+        if !json_obj
+            .get("deleted")
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false)
+        {
+            return Err(GigaError::UnexpectedResponse(
+                "File was not deleted".to_owned(),
+            ));
         }
 
         Ok(())
     }
 }
 
+#[derive(Default)]
 pub struct ClientBuilder {
     msg_cfg: Option<MessageConfig>,
     basic_token: Option<String>,
+    scope: Option<Scope>,
+    credentials: Option<Arc<dyn CredentialProvider>>,
+    token_refresh_margin: Option<Duration>,
+    base_url: Option<String>,
+    auth_url: Option<String>,
+    root_certificates: Vec<Vec<u8>>,
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    accept_invalid_certs: bool,
+    http_client: Option<reqwest::Client>,
+    file_purpose: Option<String>,
+    retry_policy: Option<RetryPolicy>,
+    rate_limit: Option<RateLimit>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    request_options: RequestOptions,
 }
 
 impl ClientBuilder {
@@ -366,6 +759,21 @@ impl ClientBuilder {
         Self {
             msg_cfg: None,
             basic_token: None,
+            scope: None,
+            credentials: None,
+            token_refresh_margin: None,
+            base_url: None,
+            auth_url: None,
+            root_certificates: Vec::new(),
+            identity: None,
+            accept_invalid_certs: false,
+            http_client: None,
+            file_purpose: None,
+            retry_policy: None,
+            rate_limit: None,
+            connect_timeout: None,
+            read_timeout: None,
+            request_options: RequestOptions::default(),
         }
     }
     pub fn set_msg_cfg(mut self, msg_cfg: MessageConfig) -> Self {
@@ -376,13 +784,176 @@ impl ClientBuilder {
         self.basic_token = basic_token.to_owned().into();
         self
     }
-    pub fn build(self) -> GigaClient {
-        GigaClient {
-            basic_token: self.basic_token.expect("Token must be set"),
-            auth_token: Arc::new(None),
+    /// Sets the basic token out of a client_id/client_secret pair, an alternative to set_basic_token
+    pub fn set_credentials(mut self, client_id: &str, client_secret: &str) -> Self {
+        self.basic_token = encode_basic(client_id, client_secret).into();
+        self
+    }
+    /// Sets the OAuth scope of the account, GIGACHAT_API_PERS by default
+    pub fn set_scope(mut self, scope: Scope) -> Self {
+        self.scope = scope.into();
+        self
+    }
+    /// Sets where access tokens come from, takes priority over the basic token
+    pub fn set_credential_provider(mut self, provider: impl CredentialProvider + 'static) -> Self {
+        self.credentials = Some(Arc::new(provider));
+        self
+    }
+    /// Sets how long before expiration the access token gets refreshed, 60 seconds by default
+    pub fn set_token_refresh_margin(mut self, margin: Duration) -> Self {
+        self.token_refresh_margin = margin.into();
+        self
+    }
+    /// Sets the base URL of the GigaChat API, e.g. for an on-premise installation, a proxy or a mock server
+    pub fn set_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_owned().into();
+        self
+    }
+    /// Sets the base URL of the OAuth server, which issues access tokens
+    pub fn set_auth_url(mut self, auth_url: &str) -> Self {
+        self.auth_url = auth_url.trim_end_matches('/').to_owned().into();
+        self
+    }
+    /// Adds a trusted root certificate in PEM format, e.g. the Russian Trusted Root CA, which GigaChat certificates are issued by
+    pub fn add_root_certificate_pem(mut self, pem: &[u8]) -> Self {
+        self.root_certificates.push(pem.to_vec());
+        self
+    }
+    /// Sets a PEM client certificate chain and a PKCS #8 PEM key, used for mTLS authentication
+    ///
+    /// If no token or credential provider is set, requests are sent without the `Authorization` header
+    pub fn set_identity_pem(mut self, cert: &[u8], key: &[u8]) -> Self {
+        self.identity = Some((cert.to_vec(), key.to_vec()));
+        self
+    }
+    /// Disables TLS certificate verification, never use it outside of testing
+    pub fn set_danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+    /// Sets the reqwest client used for all requests, e.g. with custom pool, proxy or timeout settings
+    ///
+    /// TLS, connect and read timeout options of the builder can't be applied to it, so `build` fails if any of them is set too
+    pub fn set_http_client(mut self, client: reqwest::Client) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+    /// Sets the purpose uploaded files are stored with, `general` by default
+    pub fn set_file_purpose(mut self, purpose: &str) -> Self {
+        self.file_purpose = purpose.to_owned().into();
+        self
+    }
+    /// Sets how failed requests are retried, by default they are not
+    pub fn set_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy.into();
+        self
+    }
+    /// Sets limits of the chat requests, calls over them wait instead of failing
+    ///
+    /// The limits are shared by all clones of the client
+    pub fn set_rate_limit(mut self, limits: RateLimit) -> Self {
+        self.rate_limit = limits.into();
+        self
+    }
+    /// Sets how long connecting to the server can take
+    pub fn set_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout.into();
+        self
+    }
+    /// Sets how long the server can stay silent while a response is being read
+    pub fn set_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout.into();
+        self
+    }
+    /// Sets how long a whole call can take, retries included, see `RequestOptions`
+    pub fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.request_options = self.request_options.set_timeout(timeout);
+        self
+    }
+    /// Sets how long a single attempt of a request can take, see `RequestOptions`
+    pub fn set_attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.request_options = self.request_options.set_attempt_timeout(timeout);
+        self
+    }
+    /// Sets how long a streamed response can go without a new chunk
+    pub fn set_stream_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.request_options = self.request_options.set_stream_idle_timeout(timeout);
+        self
+    }
+    /// Builds a client, fails if neither the token, the credential provider nor the client certificate is set
+    pub fn build(self) -> Result<GigaClient> {
+        let httpclient = match &self.http_client {
+            Some(_) if self.has_client_options() => {
+                return Err(GigaError::Config(
+                    "TLS and connection timeout options can't be applied to a custom HTTP client, configure it beforehand".to_owned(),
+                ))
+            }
+            Some(client) => client.clone(),
+            None => self.client_config()?.client()?,
+        };
+
+        let credentials: Option<Arc<dyn CredentialProvider>> =
+            match (self.credentials, self.basic_token) {
+                (Some(provider), _) => Some(provider),
+                (None, Some(basic_token)) => Some(Arc::new(OAuthCredentials::new(
+                    &basic_token,
+                    self.scope.unwrap_or_default(),
+                ))),
+                (None, None) if self.identity.is_some() => None,
+                (None, None) => return Err(GigaError::Config("Token must be set".to_owned())),
+            };
+
+        Ok(GigaClient {
+            credentials,
+            auth_token: Arc::new(Mutex::new(None)),
+            token_refresh_margin: self.token_refresh_margin.unwrap_or(TOKEN_REFRESH_MARGIN),
+            base_url: self.base_url.unwrap_or_else(|| BASE_URL.to_owned()),
+            auth_url: self.auth_url.unwrap_or_else(|| BASE_URL_AUTH.to_owned()),
             message_cfg: self.msg_cfg.unwrap_or_default(),
-            uuid: uuid::Uuid::new_v4().to_string(),
-            httpclient: HttpClient::new(),
-        }
+            file_purpose: self
+                .file_purpose
+                .unwrap_or_else(|| DEFAULT_FILE_PURPOSE.to_owned()),
+            httpclient: HttpClient::new(
+                httpclient,
+                self.retry_policy.unwrap_or_else(RetryPolicy::none),
+                self.request_options,
+            ),
+            rate_limiter: self
+                .rate_limit
+                .map(|limits| Arc::new(RateLimiter::new(limits))),
+        })
+    }
+
+    /// Whether any of the options, that are applied to the reqwest client, is set
+    fn has_client_options(&self) -> bool {
+        !self.root_certificates.is_empty()
+            || self.identity.is_some()
+            || self.accept_invalid_certs
+            || self.connect_timeout.is_some()
+            || self.read_timeout.is_some()
+    }
+
+    /// Parses the certificates passed to the builder
+    fn client_config(&self) -> Result<ClientConfig> {
+        let root_certificates = self
+            .root_certificates
+            .iter()
+            .map(|pem| Certificate::from_pem(pem))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|why| GigaError::Config(format!("Invalid root certificate: {}", why)))?;
+        let identity = self
+            .identity
+            .as_ref()
+            .map(|(cert, key)| Identity::from_pkcs8_pem(cert, key))
+            .transpose()
+            .map_err(|why| GigaError::Config(format!("Invalid client certificate: {}", why)))?;
+
+        Ok(ClientConfig {
+            root_certificates,
+            identity,
+            accept_invalid_certs: self.accept_invalid_certs,
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+        })
     }
 }
@@ -0,0 +1,196 @@
+use std::{collections::HashMap, future::Future};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::{future::BoxFuture, FutureExt};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use serde::Deserialize;
+
+use crate::error::Result;
+
+use super::{
+    access_token::{AccessToken, Scope},
+    httpclient::HttpClient,
+};
+
+/// Encodes a login/password pair the way HTTP Basic auth expects it
+pub(crate) fn encode_basic(login: &str, password: &str) -> String {
+    BASE64_STANDARD.encode(format!("{}:{}", login, password))
+}
+
+/// Things a credential provider may need to get a token, passed by the client
+pub struct AuthContext<'a> {
+    pub(crate) httpclient: &'a HttpClient,
+    pub(crate) base_url: &'a str,
+    pub(crate) auth_url: &'a str,
+}
+
+impl AuthContext<'_> {
+    /// HTTP client used by the GigaClient
+    pub fn http_client(&self) -> &reqwest::Client {
+        self.httpclient.inner()
+    }
+    /// Base URL of the GigaChat API, e.g. `https://gigachat.devices.sberbank.ru/api`
+    pub fn base_url(&self) -> &str {
+        self.base_url
+    }
+    /// Base URL of the OAuth server, e.g. `https://ngw.devices.sberbank.ru:9443/api`
+    pub fn auth_url(&self) -> &str {
+        self.auth_url
+    }
+}
+
+/// Source of access tokens used by every request to the API
+///
+/// The client caches the token and only asks for a new one when the cached one is missing, about to expire or rejected
+pub trait CredentialProvider: Send + Sync {
+    fn fetch_token<'a>(&'a self, ctx: AuthContext<'a>) -> BoxFuture<'a, Result<AccessToken>>;
+}
+
+/// Gets tokens from the GigaChat OAuth server, using the basic token (base64 of `client_id:client_secret`)
+pub struct OAuthCredentials {
+    basic_token: String,
+    scope: Scope,
+}
+
+impl OAuthCredentials {
+    pub fn new(basic_token: &str, scope: Scope) -> Self {
+        Self {
+            basic_token: basic_token.to_owned(),
+            scope,
+        }
+    }
+    /// Create credentials out of a client_id/client_secret pair
+    pub fn from_client_secret(client_id: &str, client_secret: &str, scope: Scope) -> Self {
+        Self {
+            basic_token: encode_basic(client_id, client_secret),
+            scope,
+        }
+    }
+}
+
+impl CredentialProvider for OAuthCredentials {
+    fn fetch_token<'a>(&'a self, ctx: AuthContext<'a>) -> BoxFuture<'a, Result<AccessToken>> {
+        async move {
+            let mut headers = HeaderMap::new();
+            headers.append(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/x-www-form-urlencoded"),
+            );
+            headers.append(ACCEPT, HeaderValue::from_static("application/json"));
+            headers.append(
+                "RqUID",
+                HeaderValue::from_str(&uuid::Uuid::new_v4().to_string())?,
+            );
+            headers.append(
+                AUTHORIZATION,
+                HeaderValue::from_str(&("Basic ".to_owned() + &self.basic_token))?,
+            );
+            let mut form_data: HashMap<String, String> = HashMap::new();
+            form_data.insert("scope".to_owned(), self.scope.to_string());
+
+            ctx.httpclient
+                .post_form(&(ctx.auth_url.to_owned() + "/v2/oauth"), form_data, headers)
+                .await
+        }
+        .boxed()
+    }
+}
+
+/// Token issued by the `/v1/token` endpoint of on-premise installations
+#[derive(Deserialize)]
+struct PasswordToken {
+    tok: String,
+    exp: u64,
+}
+
+/// Gets tokens from the GigaChat API itself using a username and a password, used by on-premise installations
+pub struct PasswordCredentials {
+    user: String,
+    password: String,
+}
+
+impl PasswordCredentials {
+    pub fn new(user: &str, password: &str) -> Self {
+        Self {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+}
+
+impl CredentialProvider for PasswordCredentials {
+    fn fetch_token<'a>(&'a self, ctx: AuthContext<'a>) -> BoxFuture<'a, Result<AccessToken>> {
+        async move {
+            let mut headers = HeaderMap::new();
+            headers.append(ACCEPT, HeaderValue::from_static("application/json"));
+            headers.append(
+                AUTHORIZATION,
+                HeaderValue::from_str(
+                    &("Basic ".to_owned() + &encode_basic(&self.user, &self.password)),
+                )?,
+            );
+
+            let tok: PasswordToken = ctx
+                .httpclient
+                .post_data(
+                    &(ctx.base_url.to_owned() + "/v1/token"),
+                    String::new(),
+                    headers,
+                )
+                .await?;
+
+            Ok(AccessToken {
+                access_token: tok.tok,
+                expires_at: tok.exp,
+            })
+        }
+        .boxed()
+    }
+}
+
+/// An already issued token, e.g. by a sidecar, which is used as is and never refreshed
+pub struct StaticToken {
+    token: AccessToken,
+}
+
+impl StaticToken {
+    pub fn new(access_token: &str) -> Self {
+        Self {
+            token: AccessToken {
+                access_token: access_token.to_owned(),
+                expires_at: u64::MAX,
+            },
+        }
+    }
+}
+
+impl CredentialProvider for StaticToken {
+    fn fetch_token<'a>(&'a self, _ctx: AuthContext<'a>) -> BoxFuture<'a, Result<AccessToken>> {
+        futures::future::ready(Ok(self.token.clone())).boxed()
+    }
+}
+
+/// Gets tokens by calling a closure, for the cases the other providers don't cover
+pub struct FnProvider<F> {
+    f: F,
+}
+
+impl<F, Fut> FnProvider<F>
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<AccessToken>> + Send + 'static,
+{
+    pub fn new(f: F) -> Self {
+        Self { f }
+    }
+}
+
+impl<F, Fut> CredentialProvider for FnProvider<F>
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<AccessToken>> + Send + 'static,
+{
+    fn fetch_token<'a>(&'a self, _ctx: AuthContext<'a>) -> BoxFuture<'a, Result<AccessToken>> {
+        (self.f)().boxed()
+    }
+}
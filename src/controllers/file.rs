@@ -1,5 +1,75 @@
 use serde::Deserialize;
 
+use crate::error::{GigaError, Result};
+
+/// Extensions of the images, that the models accept as attachments
+const IMAGE_EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "tiff", "bmp"];
+
+const MB: u64 = 1024 * 1024;
+/// Formats the storage accepts: extension, MIME type and the maximum size
+const SUPPORTED_FORMATS: [(&str, &str, u64); 19] = [
+    ("txt", "text/plain", 40 * MB),
+    ("doc", "application/msword", 40 * MB),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        40 * MB,
+    ),
+    ("pdf", "application/pdf", 40 * MB),
+    ("epub", "application/epub+zip", 40 * MB),
+    ("ppt", "application/vnd.ms-powerpoint", 40 * MB),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        40 * MB,
+    ),
+    ("jpg", "image/jpeg", 15 * MB),
+    ("jpeg", "image/jpeg", 15 * MB),
+    ("png", "image/png", 15 * MB),
+    ("tiff", "image/tiff", 15 * MB),
+    ("bmp", "image/bmp", 15 * MB),
+    ("mp4", "audio/mp4", 35 * MB),
+    ("mp3", "audio/mpeg", 35 * MB),
+    ("m4a", "audio/x-m4a", 35 * MB),
+    ("wav", "audio/wav", 35 * MB),
+    ("weba", "audio/webm", 35 * MB),
+    ("ogg", "audio/ogg", 35 * MB),
+    ("opus", "audio/opus", 35 * MB),
+];
+
+/// Purpose of an uploaded file, `general` is the one, that can be attached to messages
+pub const DEFAULT_FILE_PURPOSE: &str = "general";
+
+/// Returns the MIME type of a supported format by the file name
+pub(crate) fn mime_from_filename(filename: &str) -> Option<&'static str> {
+    let (_, ext) = filename.rsplit_once('.')?;
+    let ext = ext.to_lowercase();
+    SUPPORTED_FORMATS
+        .iter()
+        .find(|(format_ext, _, _)| *format_ext == ext)
+        .map(|(_, mime, _)| *mime)
+}
+
+/// Checks that the storage accepts files of the type and size before uploading them
+pub(crate) fn check_upload(mime: &str, size: u64) -> Result<()> {
+    let Some((_, _, max_size)) = SUPPORTED_FORMATS
+        .iter()
+        .find(|(_, format_mime, _)| *format_mime == mime)
+    else {
+        return Err(GigaError::InvalidInput(format!(
+            "Files of type {} are not supported",
+            mime
+        )));
+    };
+    if size > *max_size {
+        return Err(GigaError::InvalidInput(format!(
+            "File of type {} is {} bytes, at most {} bytes are allowed",
+            mime, size, max_size
+        )));
+    }
+    Ok(())
+}
+
 /// Struct used for storing information about a file
 #[derive(Deserialize, Debug)]
 pub struct GigaFile {
@@ -11,3 +81,20 @@ pub struct GigaFile {
     pub purpose: String,
     pub access_policy: String,
 }
+
+impl GigaFile {
+    /// Whether the file can be attached to a message, only files uploaded with the `general` purpose can
+    ///
+    /// `access_policy` is not checked: it only tells whether the file is `private` to the account or `public`,
+    /// and the API lets both be attached, a file of another account is not found at all
+    pub fn is_attachable(&self) -> bool {
+        self.purpose == DEFAULT_FILE_PURPOSE
+    }
+
+    /// Whether the file is an image, judging by the extension
+    pub fn is_image(&self) -> bool {
+        self.filename
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+    }
+}
@@ -1,93 +1,199 @@
-use anyhow::anyhow;
-use reqwest::multipart::Form;
+use std::{future::Future, time::Duration};
+
+use async_stream::try_stream;
+use futures::{future::ready, Stream, StreamExt};
+use reqwest::{
+    header::RETRY_AFTER, multipart::Form, Certificate, Identity, RequestBuilder, Response,
+};
 use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 
-/// Wrapper for a HTTP client, which sends request to the GigaChat API
+use crate::error::{ApiError, ErrorBody, GigaError, Result};
 
-pub struct HttpClient {
-    httpclient: reqwest::Client,
+use super::retry::RetryPolicy;
+
+/// TLS and connection settings of the underlying reqwest client
+#[derive(Default)]
+pub(crate) struct ClientConfig {
+    pub root_certificates: Vec<Certificate>,
+    pub identity: Option<Identity>,
+    pub accept_invalid_certs: bool,
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
 }
 
-impl Clone for HttpClient {
-    fn clone(&self) -> Self {
-        Self {
-            httpclient: reqwest::ClientBuilder::new()
-                .danger_accept_invalid_certs(true)
-                .build()
-                .expect("reqwest Client could not be created"),
+impl ClientConfig {
+    pub(crate) fn client(&self) -> Result<reqwest::Client> {
+        let mut builder =
+            reqwest::ClientBuilder::new().danger_accept_invalid_certs(self.accept_invalid_certs);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        for cert in &self.root_certificates {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Settings, that can be changed for a single call with `GigaClient::with_options`
+///
+/// Connect and read timeouts are settings of the connection pool, so they are only set in `ClientBuilder` and can't be overridden per call.
+///
+/// With a retry policy a call can take up to `max_attempts` times the attempt timeout, plus the delays between attempts,
+/// the total timeout bounds the whole call instead
+#[derive(Clone, Debug, Default)]
+pub struct RequestOptions {
+    timeout: Option<Duration>,
+    attempt_timeout: Option<Duration>,
+    stream_idle_timeout: Option<Duration>,
+}
+
+impl RequestOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets how long a whole call can take, retries and reading a streamed response included
+    pub fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// Sets how long a single attempt of a request can take, from sending it to reading the whole response
+    pub fn set_attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.attempt_timeout = Some(timeout);
+        self
+    }
+    /// Sets how long a streamed response can go without a new chunk
+    pub fn set_stream_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.stream_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Options, where the ones set in `other` replace the current ones
+    pub(crate) fn merge(&self, other: &RequestOptions) -> RequestOptions {
+        RequestOptions {
+            timeout: other.timeout.or(self.timeout),
+            attempt_timeout: other.attempt_timeout.or(self.attempt_timeout),
+            stream_idle_timeout: other.stream_idle_timeout.or(self.stream_idle_timeout),
         }
     }
 }
 
+/// Wrapper for a HTTP client, which sends request to the GigaChat API
+///
+/// Clones share the connection pool of the underlying reqwest client
+#[derive(Clone)]
+pub struct HttpClient {
+    httpclient: reqwest::Client,
+    retry_policy: RetryPolicy,
+    options: RequestOptions,
+}
+
 impl HttpClient {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        httpclient: reqwest::Client,
+        retry_policy: RetryPolicy,
+        options: RequestOptions,
+    ) -> Self {
         Self {
-            httpclient: reqwest::ClientBuilder::new()
-                .danger_accept_invalid_certs(true)
-                .build()
-                .expect("reqwest Client could not be created"),
+            httpclient,
+            retry_policy,
+            options,
         }
     }
 
+    /// Returns a copy of the client with some of the options replaced
+    pub(crate) fn with_options(&self, options: &RequestOptions) -> HttpClient {
+        let mut client = self.clone();
+        client.options = self.options.merge(options);
+        client
+    }
+
+    /// Returns the underlying reqwest client
+    pub(crate) fn inner(&self) -> &reqwest::Client {
+        &self.httpclient
+    }
+
     pub(crate) async fn post_form<T, R>(
         &self,
         api: &str,
         body: T,
         headers: reqwest::header::HeaderMap,
-    ) -> anyhow::Result<R>
+    ) -> Result<R>
     where
         R: for<'a> Deserialize<'a>,
         T: Serialize,
     {
-        let resp = self
-            .httpclient
-            .post(api)
-            .headers(headers)
-            .form(&body)
-            .send()
-            .await
-            .map_err(|why| anyhow!("could not send request {}", why))?;
-        if !resp.status().is_success() {
-            return Err(anyhow!(
-                "Request is not successful: {}",
-                resp.status().to_string()
-            ));
-        }
+        let call = async {
+            let resp = self
+                .send(true, || {
+                    Ok(self
+                        .httpclient
+                        .post(api)
+                        .headers(headers.clone())
+                        .form(&body))
+                })
+                .await?;
 
-        let resp_str: String = resp.text().await?;
-        let r: R = serde_json::from_str(&resp_str)
-            .map_err(|why| anyhow!("Could not deserialize: {}", why))?;
-        Ok(r)
+            Self::parse(resp).await
+        };
+        Self::before_deadline(self.deadline(), call).await
     }
 
-    pub(crate) async fn post_multipart<R>(
+    /// Sends a multipart form, which is built again for every attempt
+    ///
+    /// Uploads are not idempotent, so they are only retried if the API surely did not store the file
+    pub(crate) async fn post_multipart<F, Fut, R>(
         &self,
         api: &str,
-        body: Form,
+        form: F,
         headers: reqwest::header::HeaderMap,
-    ) -> anyhow::Result<R>
+    ) -> Result<R>
     where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Form>>,
         R: for<'a> Deserialize<'a>,
     {
-        let resp = self
-            .httpclient
-            .post(api)
-            .headers(headers)
-            .multipart(body)
-            .send()
-            .await
-            .map_err(|why| anyhow!("could not send request {}", why))?;
-        if !resp.status().is_success() {
-            return Err(anyhow!(
-                "Request is not successful: {}",
-                resp.status().to_string()
-            ));
-        }
+        let (form, headers) = (&form, &headers);
+        let call = async {
+            let resp = self
+                .send_with(false, move || async move {
+                    Ok(self
+                        .httpclient
+                        .post(api)
+                        .headers(headers.clone())
+                        .multipart(form().await?))
+                })
+                .await?;
 
-        let resp_str: String = resp.text().await?;
-        let r: R = serde_json::from_str(&resp_str)
-            .map_err(|why| anyhow!("Could not deserialize: {}", why))?;
-        Ok(r)
+            Self::parse(resp).await
+        };
+        Self::before_deadline(self.deadline(), call).await
+    }
+
+    /// Sends a multipart form, that can't be built again (e.g. a streamed one), without retries
+    pub(crate) async fn post_multipart_once<R>(
+        &self,
+        api: &str,
+        form: Form,
+        headers: reqwest::header::HeaderMap,
+    ) -> Result<R>
+    where
+        R: for<'a> Deserialize<'a>,
+    {
+        let call = async {
+            let request = self.httpclient.post(api).headers(headers).multipart(form);
+            let resp = self.send_once(request).await?;
+
+            Self::parse(resp).await
+        };
+        Self::before_deadline(self.deadline(), call).await
     }
 
     pub(crate) async fn post_data<S, R>(
@@ -95,62 +201,353 @@ impl HttpClient {
         api: &str,
         body: S,
         headers: reqwest::header::HeaderMap,
-    ) -> anyhow::Result<R>
+    ) -> Result<R>
     where
         R: for<'a> Deserialize<'a>,
+        S: Clone,
         reqwest::Body: From<S>,
     {
-        let resp = self
-            .httpclient
-            .post(api)
-            .headers(headers)
-            .body(body)
-            .send()
-            .await
-            .map_err(|why| anyhow!("Sending failure: {}", why))?;
-
-        if !resp.status().is_success() {
-            return Err(anyhow!(
-                "Request is not successful: {}",
-                resp.status().to_string()
-            ));
-        }
+        let call = async {
+            let resp = self
+                .send(true, || {
+                    Ok(self
+                        .httpclient
+                        .post(api)
+                        .headers(headers.clone())
+                        .body(body.clone()))
+                })
+                .await?;
 
-        let resp_str = resp.text().await?;
+            Self::parse(resp).await
+        };
+        Self::before_deadline(self.deadline(), call).await
+    }
+
+    pub(crate) async fn get<R>(&self, api: &str, headers: reqwest::header::HeaderMap) -> Result<R>
+    where
+        R: for<'a> Deserialize<'a>,
+    {
+        let call = async {
+            let resp = self
+                .send(true, || {
+                    Ok(self.httpclient.get(api).headers(headers.clone()))
+                })
+                .await?;
 
-        let r: R = serde_json::from_str(&resp_str)
-            .map_err(|why| anyhow!("Could not deserialize {}", why))?;
+            Self::parse(resp).await
+        };
+        Self::before_deadline(self.deadline(), call).await
+    }
 
-        Ok(r)
+    /// Sends a GET request and returns the raw response body
+    pub(crate) async fn get_bytes(
+        &self,
+        api: &str,
+        headers: reqwest::header::HeaderMap,
+    ) -> Result<Vec<u8>> {
+        let call = async {
+            let resp = self
+                .send(true, || {
+                    Ok(self.httpclient.get(api).headers(headers.clone()))
+                })
+                .await?;
+
+            Ok(resp.bytes().await?.to_vec())
+        };
+        Self::before_deadline(self.deadline(), call).await
+    }
+
+    /// Sends a GET request and returns the response body as a stream of chunks
+    pub(crate) async fn get_stream(
+        &self,
+        api: &str,
+        headers: reqwest::header::HeaderMap,
+    ) -> Result<impl Stream<Item = Result<impl AsRef<[u8]>>>> {
+        let deadline = self.deadline();
+        let resp = Self::before_deadline(
+            deadline,
+            self.send(true, || {
+                Ok(self.httpclient.get(api).headers(headers.clone()))
+            }),
+        )
+        .await?;
+
+        Ok(self.guard_stream(resp.bytes_stream(), deadline))
     }
 
-    pub(crate) async fn get<R>(
+    /// Sends a request and parses the `text/event-stream` response, yielding every `data:` event until `[DONE]`
+    pub(crate) async fn post_stream<S, R>(
         &self,
         api: &str,
+        body: S,
         headers: reqwest::header::HeaderMap,
-    ) -> anyhow::Result<R>
+    ) -> Result<impl Stream<Item = Result<R>>>
     where
         R: for<'a> Deserialize<'a>,
+        S: Clone,
+        reqwest::Body: From<S>,
     {
-        let resp = self
-            .httpclient
-            .get(api)
-            .headers(headers)
-            .send()
-            .await
-            .map_err(|why| anyhow!("Sending failure: {}", why))?;
-
-        if !resp.status().is_success() {
-            return Err(anyhow!(
-                "Request is not successful: {}",
-                resp.status().to_string()
-            ));
+        // Only establishing the stream is retried, a broken stream is returned as an error
+        let deadline = self.deadline();
+        let resp = Self::before_deadline(
+            deadline,
+            self.send(true, || {
+                Ok(self
+                    .httpclient
+                    .post(api)
+                    .headers(headers.clone())
+                    .body(body.clone()))
+            }),
+        )
+        .await?;
+
+        let chunks = self.guard_stream(resp.bytes_stream(), deadline);
+        Ok(try_stream! {
+            futures::pin_mut!(chunks);
+            let mut parser = EventParser::default();
+            while let Some(chunk) = chunks.next().await {
+                for data in parser.push(&chunk?) {
+                    let r: R = serde_json::from_str(&data)?;
+                    yield r;
+                }
+                if parser.is_done() {
+                    return;
+                }
+            }
+            if let Some(data) = parser.finish() {
+                let r: R = serde_json::from_str(&data)?;
+                yield r;
+            }
+        })
+    }
+
+    /// Sends the request built by the closure, retrying it according to the retry policy
+    async fn send<F>(&self, idempotent: bool, request: F) -> Result<Response>
+    where
+        F: Fn() -> Result<RequestBuilder>,
+    {
+        self.send_with(idempotent, || ready(request())).await
+    }
+
+    /// Same as `send`, but the request is built asynchronously, e.g. when a file has to be opened
+    async fn send_with<F, Fut>(&self, idempotent: bool, request: F) -> Result<Response>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<RequestBuilder>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match self.send_once(request().await?).await {
+                Err(err) if self.can_retry(attempt, &err, idempotent) => {
+                    tokio::time::sleep(self.retry_policy.delay(attempt, &err)).await;
+                    attempt += 1;
+                }
+                resp => return resp,
+            }
+        }
+    }
+
+    async fn send_once(&self, request: RequestBuilder) -> Result<Response> {
+        let request = match self.options.attempt_timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        };
+        let resp = request.send().await?;
+        Self::check_status(resp).await
+    }
+
+    fn can_retry(&self, attempt: u32, err: &GigaError, idempotent: bool) -> bool {
+        attempt < self.retry_policy.max_attempts()
+            && self.retry_policy.should_retry(err, idempotent)
+    }
+
+    /// When the total timeout of a call, that starts now, runs out, along with the timeout itself
+    fn deadline(&self) -> Option<(Instant, Duration)> {
+        self.options
+            .timeout
+            .map(|timeout| (Instant::now() + timeout, timeout))
+    }
+
+    /// Fails the call with `GigaError::CallTimeout`, if it does not complete before the deadline
+    async fn before_deadline<T>(
+        deadline: Option<(Instant, Duration)>,
+        call: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        match deadline {
+            Some((deadline, timeout)) => tokio::time::timeout_at(deadline, call)
+                .await
+                .map_err(|_| GigaError::CallTimeout(timeout))?,
+            None => call.await,
+        }
+    }
+
+    /// Fails the stream with `GigaError::StreamIdle`, if no chunk arrives within the stream idle timeout,
+    /// or with `GigaError::CallTimeout`, once the deadline of the call passes
+    fn guard_stream<S, T>(
+        &self,
+        chunks: S,
+        deadline: Option<(Instant, Duration)>,
+    ) -> impl Stream<Item = Result<T>>
+    where
+        S: Stream<Item = reqwest::Result<T>>,
+    {
+        let idle_timeout = self.options.stream_idle_timeout;
+        try_stream! {
+            futures::pin_mut!(chunks);
+            loop {
+                let next = async {
+                    match idle_timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, chunks.next())
+                            .await
+                            .map_err(|_| GigaError::StreamIdle(timeout)),
+                        None => Ok(chunks.next().await),
+                    }
+                };
+                let chunk = Self::before_deadline(deadline, next).await?;
+                match chunk {
+                    Some(chunk) => yield chunk?,
+                    None => break,
+                }
+            }
         }
+    }
 
-        let resp_str: String = resp.text().await?;
+    /// Reads the response body as JSON
+    async fn parse<R>(resp: Response) -> Result<R>
+    where
+        R: for<'a> Deserialize<'a>,
+    {
+        let resp_str = resp.text().await?;
 
-        let r: R = serde_json::from_str(&resp_str)
-            .map_err(|why| anyhow!("Could not deserialize {}", why))?;
+        let r: R = serde_json::from_str(&resp_str)?;
         Ok(r)
     }
+
+    /// Turns a non-successful response into a `GigaError`, using the error body GigaChat sends
+    async fn check_status(resp: Response) -> Result<Response> {
+        let http_status = resp.status();
+        if http_status.is_success() {
+            return Ok(resp);
+        }
+
+        let retry_after = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let body = ErrorBody::from_text(&resp.text().await.unwrap_or_default());
+
+        Err(GigaError::from(ApiError {
+            http_status,
+            status: body.status,
+            message: body.message,
+            retry_after,
+        }))
+    }
+}
+
+/// Splits a `text/event-stream` body into the payloads of its `data:` lines, until `[DONE]`
+#[derive(Default)]
+struct EventParser {
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl EventParser {
+    /// Feeds a chunk of the body, returning payloads of the lines, that are complete now
+    ///
+    /// Events may be split across chunks, so an incomplete line is kept until the rest of it arrives
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            if let Some(data) = self.parse_line(&line) {
+                events.push(data);
+            }
+        }
+        events
+    }
+
+    /// Returns the payload of the last line, if the body does not end with a newline
+    fn finish(&mut self) -> Option<String> {
+        let line = std::mem::take(&mut self.buffer);
+        self.parse_line(&line)
+    }
+
+    /// Whether `[DONE]` was received, nothing is parsed after it
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn parse_line(&mut self, line: &[u8]) -> Option<String> {
+        if self.done {
+            return None;
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let data = line.trim().strip_prefix("data:")?.trim();
+        if data == "[DONE]" {
+            self.done = true;
+            return None;
+        }
+        Some(data.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventParser;
+
+    #[test]
+    fn events_split_across_chunks() {
+        let mut parser = EventParser::default();
+        assert!(parser.push(b"data: {\"a\":").is_empty());
+        assert_eq!(
+            parser.push(b"1}\n\ndata: {\"b\":2}\n"),
+            ["{\"a\":1}", "{\"b\":2}"]
+        );
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let mut parser = EventParser::default();
+        assert_eq!(parser.push(b"data: 1\r\n\r\ndata:2\r\n"), ["1", "2"]);
+    }
+
+    #[test]
+    fn other_fields_are_skipped() {
+        let mut parser = EventParser::default();
+        assert_eq!(
+            parser.push(b"event: message\nid: 5\n: comment\ndata: 1\n"),
+            ["1"]
+        );
+    }
+
+    #[test]
+    fn nothing_after_done() {
+        let mut parser = EventParser::default();
+        assert_eq!(parser.push(b"data: 1\ndata: [DONE]\ndata: 2\n"), ["1"]);
+        assert!(parser.is_done());
+        assert!(parser.push(b"data: 3\n").is_empty());
+        assert_eq!(parser.finish(), None);
+    }
+
+    #[test]
+    fn last_line_without_newline() {
+        let mut parser = EventParser::default();
+        assert_eq!(parser.push(b"data: 1\ndata: 2"), ["1"]);
+        assert_eq!(parser.finish().as_deref(), Some("2"));
+        assert_eq!(parser.finish(), None);
+    }
+
+    #[test]
+    fn done_without_newline() {
+        let mut parser = EventParser::default();
+        assert!(parser.push(b"data: [DONE]").is_empty());
+        assert_eq!(parser.finish(), None);
+        assert!(parser.is_done());
+    }
 }
@@ -1,5 +1,8 @@
 pub mod access_token;
 pub mod chat;
 pub mod client;
+pub mod credentials;
 pub mod file;
-pub mod httpclient;
\ No newline at end of file
+pub mod httpclient;
+pub mod rate_limit;
+pub mod retry;
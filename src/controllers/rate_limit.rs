@@ -0,0 +1,213 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+use crate::http::message::Message;
+
+/// Limits of the client-side rate limiter, every limit is optional
+///
+/// Calls over the limit wait in a queue instead of failing
+#[derive(Clone, Debug, Default)]
+pub struct RateLimit {
+    max_concurrent: Option<usize>,
+    requests_per_second: Option<u32>,
+    tokens_per_minute: Option<u32>,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets how many chat requests can be in flight at once, a stream counts until it is dropped
+    pub fn set_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.max_concurrent = Some(max_concurrent.max(1));
+        self
+    }
+    pub fn set_requests_per_second(mut self, requests: u32) -> Self {
+        self.requests_per_second = Some(requests.max(1));
+        self
+    }
+    /// Sets how many tokens can be spent per minute, the tokens of a request are estimated before sending it
+    pub fn set_tokens_per_minute(mut self, tokens: u32) -> Self {
+        self.tokens_per_minute = Some(tokens.max(1));
+        self
+    }
+}
+
+/// Start times of requests along with the amount they spent
+type Window = VecDeque<(Instant, u32)>;
+
+/// Queue of chat requests, shared by all clones of a client
+pub(crate) struct RateLimiter {
+    limits: RateLimit,
+    concurrent: Option<Arc<Semaphore>>,
+    // Requests during the last second
+    requests: Mutex<Window>,
+    // Requests during the last minute along with their estimated tokens
+    tokens: Mutex<Window>,
+    queued: AtomicUsize,
+}
+
+/// Keeps a concurrency slot taken until dropped
+pub(crate) struct RatePermit {
+    _concurrent: Option<OwnedSemaphorePermit>,
+}
+
+/// Decrements the queue depth once the call leaves the queue, even if it is cancelled
+struct QueueGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: RateLimit) -> Self {
+        Self {
+            concurrent: limits
+                .max_concurrent
+                .map(|max| Arc::new(Semaphore::new(max))),
+            limits,
+            requests: Mutex::new(VecDeque::new()),
+            tokens: Mutex::new(VecDeque::new()),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many calls are waiting for their turn
+    pub(crate) fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Waits until a request, which is estimated to spend `tokens`, fits all the limits
+    pub(crate) async fn acquire(&self, tokens: u32) -> RatePermit {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let _guard = QueueGuard(&self.queued);
+
+        let concurrent = match &self.concurrent {
+            Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        if let Some(limit) = self.limits.requests_per_second {
+            Self::wait_for_window(&self.requests, Duration::from_secs(1), 1, |window| {
+                window.len() < limit as usize
+            })
+            .await;
+        }
+        if let Some(limit) = self.limits.tokens_per_minute {
+            Self::wait_for_window(&self.tokens, Duration::from_secs(60), tokens, |window| {
+                let spent: u32 = window.iter().map(|(_, spent)| spent).sum();
+                // A request bigger than the whole limit would wait forever, so it only waits for an empty window
+                window.is_empty() || spent.saturating_add(tokens) <= limit
+            })
+            .await;
+        }
+
+        RatePermit {
+            _concurrent: concurrent,
+        }
+    }
+
+    /// Waits until `fits` lets the request into the sliding window, then records it with the amount
+    async fn wait_for_window<F>(window: &Mutex<Window>, period: Duration, amount: u32, fits: F)
+    where
+        F: Fn(&Window) -> bool,
+    {
+        loop {
+            let wait = {
+                let mut window = window.lock().unwrap_or_else(|err| err.into_inner());
+                let now = Instant::now();
+                while window
+                    .front()
+                    .is_some_and(|(started, _)| now.duration_since(*started) >= period)
+                {
+                    window.pop_front();
+                }
+
+                if fits(&window) {
+                    window.push_back((now, amount));
+                    return;
+                }
+                // Waiting until the oldest request leaves the window
+                window
+                    .front()
+                    .map_or(period, |(started, _)| period - now.duration_since(*started))
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Rough estimation of the tokens a request spends: about 4 characters per prompt token plus the completion limit
+pub(crate) fn estimate_tokens(messages: &[Message], max_tokens: Option<u32>) -> u32 {
+    let chars: usize = messages
+        .iter()
+        .map(|message| message.content.chars().count())
+        .sum();
+    (chars.div_ceil(4) as u32).saturating_add(max_tokens.unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{RateLimit, RateLimiter};
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_per_second() {
+        let limiter = RateLimiter::new(RateLimit::new().set_requests_per_second(2));
+        let start = Instant::now();
+
+        limiter.acquire(0).await;
+        limiter.acquire(0).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        limiter.acquire(0).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_over_the_token_limit_waits_for_an_empty_window() {
+        let limiter = RateLimiter::new(RateLimit::new().set_tokens_per_minute(100));
+        let start = Instant::now();
+
+        limiter.acquire(50).await;
+        limiter.acquire(500).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+
+        // The big request fills the window on its own
+        limiter.acquire(1).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(120));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_call_leaves_the_queue() {
+        let limiter = RateLimiter::new(RateLimit::new().set_max_concurrent(1));
+        let _permit = limiter.acquire(0).await;
+        assert_eq!(limiter.queue_depth(), 0);
+
+        let mut waiting = Box::pin(limiter.acquire(0));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), &mut waiting)
+                .await
+                .is_err()
+        );
+        assert_eq!(limiter.queue_depth(), 1);
+
+        drop(waiting);
+        assert_eq!(limiter.queue_depth(), 0);
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+
+use crate::error::GigaError;
+
+/// Describes when and how failed requests are retried
+///
+/// Delays grow exponentially from `base_delay` up to `max_delay`, unless the API sends `Retry-After`
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    statuses: Vec<StatusCode>,
+    respect_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+            statuses: vec![
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            respect_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a policy, that retries 429 and transient 5xx errors up to 3 times
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Creates a policy, that never retries, which is what the client uses by default
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+    /// Sets how many times a request is sent at most, including the first attempt
+    pub fn set_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+    /// Sets the delay before the first retry, it doubles with every next one
+    pub fn set_base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self
+    }
+    /// Sets the upper bound of the exponential delay
+    pub fn set_max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+    /// Randomizes delays, so that several clients don't retry at the same moment
+    pub fn set_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+    /// Sets which HTTP statuses are retried
+    pub fn set_statuses(mut self, statuses: Vec<StatusCode>) -> Self {
+        self.statuses = statuses;
+        self
+    }
+    /// Sets whether the `Retry-After` header takes priority over the exponential delay, it is still capped by `max_delay`
+    pub fn set_respect_retry_after(mut self, respect: bool) -> Self {
+        self.respect_retry_after = respect;
+        self
+    }
+
+    pub(crate) fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Whether the request, that failed with the error, should be sent again
+    ///
+    /// Non-idempotent requests are only retried if the API surely did not process them
+    pub(crate) fn should_retry(&self, err: &GigaError, idempotent: bool) -> bool {
+        match err {
+            // The request never reached the server, even if connecting timed out
+            GigaError::Request(err) | GigaError::Timeout(err) if err.is_connect() => true,
+            GigaError::Request(_) | GigaError::Timeout(_) => idempotent,
+            _ => err.api_error().is_some_and(|err| {
+                self.statuses.contains(&err.http_status)
+                    && (idempotent || err.http_status == StatusCode::TOO_MANY_REQUESTS)
+            }),
+        }
+    }
+
+    /// How long to wait before the next attempt, `attempt` starts from 1
+    pub(crate) fn delay(&self, attempt: u32, err: &GigaError) -> Duration {
+        if self.respect_retry_after {
+            // The server can't make a call sleep longer than the policy allows
+            if let Some(retry_after) = err.retry_after() {
+                return retry_after.min(self.max_delay);
+            }
+        }
+
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        if self.jitter {
+            // Half of the delay is fixed, the other half is random
+            let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+            delay.mul_f64(0.5 + random / 2.0)
+        } else {
+            delay
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Duration};
+
+    use reqwest::StatusCode;
+
+    use crate::error::{ApiError, GigaError};
+
+    use super::RetryPolicy;
+
+    fn api_error(status: StatusCode, retry_after: Option<Duration>) -> GigaError {
+        GigaError::from(ApiError {
+            http_status: status,
+            status: None,
+            message: None,
+            retry_after,
+        })
+    }
+
+    #[test]
+    fn non_idempotent_requests_only_retry_429() {
+        let policy = RetryPolicy::new();
+
+        let rate_limited = api_error(StatusCode::TOO_MANY_REQUESTS, None);
+        assert!(policy.should_retry(&rate_limited, true));
+        assert!(policy.should_retry(&rate_limited, false));
+
+        let unavailable = api_error(StatusCode::SERVICE_UNAVAILABLE, None);
+        assert!(policy.should_retry(&unavailable, true));
+        assert!(!policy.should_retry(&unavailable, false));
+
+        let bad_request = api_error(StatusCode::BAD_REQUEST, None);
+        assert!(!policy.should_retry(&bad_request, true));
+    }
+
+    #[tokio::test]
+    async fn connect_errors_are_always_retried() {
+        // Nothing listens on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let err: GigaError = reqwest::get(format!("http://127.0.0.1:{port}"))
+            .await
+            .unwrap_err()
+            .into();
+
+        let policy = RetryPolicy::new();
+        assert!(policy.should_retry(&err, true));
+        assert!(policy.should_retry(&err, false));
+    }
+
+    #[tokio::test]
+    async fn other_request_errors_are_retried_if_idempotent() {
+        let err: GigaError = reqwest::get("not a url").await.unwrap_err().into();
+
+        let policy = RetryPolicy::new();
+        assert!(policy.should_retry(&err, true));
+        assert!(!policy.should_retry(&err, false));
+    }
+
+    #[test]
+    fn retry_after_is_capped_by_max_delay() {
+        let policy = RetryPolicy::new().set_max_delay(Duration::from_secs(1));
+        let err = api_error(StatusCode::TOO_MANY_REQUESTS, Some(Duration::from_secs(60)));
+        assert_eq!(policy.delay(1, &err), Duration::from_secs(1));
+
+        let err = api_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(Duration::from_millis(200)),
+        );
+        assert_eq!(policy.delay(1, &err), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn delay_grows_exponentially_up_to_max_delay() {
+        let policy = RetryPolicy::new()
+            .set_base_delay(Duration::from_millis(100))
+            .set_max_delay(Duration::from_secs(1))
+            .set_jitter(false);
+        let err = api_error(StatusCode::SERVICE_UNAVAILABLE, None);
+
+        assert_eq!(policy.delay(1, &err), Duration::from_millis(100));
+        assert_eq!(policy.delay(2, &err), Duration::from_millis(200));
+        assert_eq!(policy.delay(3, &err), Duration::from_millis(400));
+        assert_eq!(policy.delay(5, &err), Duration::from_secs(1));
+        // The multiplier overflows long before this
+        assert_eq!(policy.delay(100, &err), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_keeps_at_least_half_of_the_delay() {
+        let policy = RetryPolicy::new().set_base_delay(Duration::from_millis(100));
+        let err = api_error(StatusCode::SERVICE_UNAVAILABLE, None);
+
+        for _ in 0..100 {
+            let delay = policy.delay(2, &err);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+}
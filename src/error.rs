@@ -0,0 +1,244 @@
+use std::{fmt::Display, time::Duration};
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+/// Result type used by every API call of the library
+pub type Result<T> = std::result::Result<T, GigaError>;
+
+/// Information about a non-successful response of the GigaChat API
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    /// HTTP status of the response
+    pub http_status: StatusCode,
+    /// `status` field of the GigaChat error body, if there was one
+    pub status: Option<i64>,
+    /// `message` field of the GigaChat error body, or the body itself if it is not JSON, e.g. an HTML page of a proxy
+    pub message: Option<String>,
+    /// How long the API asked to wait before retrying, taken from the `Retry-After` header
+    pub retry_after: Option<Duration>,
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{}: {}", self.http_status, message),
+            None => write!(f, "{}", self.http_status),
+        }
+    }
+}
+
+/// Error body, that GigaChat API sends along with a non-successful status
+#[derive(Deserialize)]
+pub(crate) struct ErrorBody {
+    pub status: Option<i64>,
+    pub message: Option<String>,
+}
+
+impl ErrorBody {
+    /// Parses the body of a failed response, keeping the raw text as the message if it is not the JSON GigaChat sends
+    pub(crate) fn from_text(text: &str) -> Self {
+        serde_json::from_str(text).unwrap_or_else(|_| {
+            let text = text.trim();
+            ErrorBody {
+                status: None,
+                message: (!text.is_empty()).then(|| text.to_owned()),
+            }
+        })
+    }
+}
+
+/// Errors that can happen while interacting with the GigaChat API
+#[derive(Debug, thiserror::Error)]
+pub enum GigaError {
+    /// The credentials were rejected (401 or 403)
+    #[error("Authorization failed: {0}")]
+    Unauthorized(ApiError),
+    /// Too many requests were made (429), see `ApiError::retry_after`
+    #[error("Rate limit exceeded: {0}")]
+    RateLimited(ApiError),
+    /// The API rejected the request body (400 or 422)
+    #[error("Request is invalid: {0}")]
+    Validation(ApiError),
+    /// The token quota of the account is exhausted (402)
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(ApiError),
+    /// The requested entity (e.g. a file) does not exist (404)
+    #[error("Not found: {0}")]
+    NotFound(ApiError),
+    /// Any other non-successful response
+    #[error("Request is not successful: {0}")]
+    Api(ApiError),
+    /// The request could not be sent or the response could not be read
+    #[error("Sending failure: {0}")]
+    Request(#[source] reqwest::Error),
+    /// The request or the connection took longer than the configured timeout
+    #[error("Timed out: {0}")]
+    Timeout(#[source] reqwest::Error),
+    /// The whole call, retries included, took longer than the total timeout
+    #[error("The call took longer than {0:?}")]
+    CallTimeout(Duration),
+    /// A streamed response went without a new chunk for longer than the stream idle timeout
+    #[error("No data was received for {0:?}")]
+    StreamIdle(Duration),
+    /// The response is not the JSON that was expected
+    #[error("Could not deserialize: {0}")]
+    Deserialize(#[from] serde_json::Error),
+    /// A header value contains characters, that are not allowed in HTTP headers
+    #[error("Invalid header value: {0}")]
+    InvalidHeader(#[from] reqwest::header::InvalidHeaderValue),
+    /// A local file could not be read
+    #[error("IO failure: {0}")]
+    Io(#[from] std::io::Error),
+    /// An argument passed to the library is invalid
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    /// The client is misconfigured
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// The model called a function, that is not registered in the chat
+    #[error("Unknown function: {0}")]
+    UnknownFunction(String),
+    /// The arguments the model generated don't match the function parameters
+    #[error("Invalid arguments of function {name}: {source}")]
+    InvalidArguments {
+        name: String,
+        source: serde_json::Error,
+    },
+    /// A registered function handler returned an error
+    #[error("Function {name} failed: {source}")]
+    FunctionFailed {
+        name: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The model kept calling functions without giving an answer
+    #[error("No answer after {0} function calls")]
+    MaxStepsExceeded(usize),
+    /// The response was deserialized, but its contents don't make sense
+    #[error("Unexpected response: {0}")]
+    UnexpectedResponse(String),
+}
+
+impl GigaError {
+    /// Returns the API error details, if the error was caused by a non-successful response
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            Self::Unauthorized(err)
+            | Self::RateLimited(err)
+            | Self::Validation(err)
+            | Self::QuotaExceeded(err)
+            | Self::NotFound(err)
+            | Self::Api(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Returns the HTTP status of the response, that caused the error
+    pub fn http_status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Request(err) | Self::Timeout(err) => err.status(),
+            _ => self.api_error().map(|err| err.http_status),
+        }
+    }
+
+    /// Returns how long the API asked to wait before retrying
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.api_error().and_then(|err| err.retry_after)
+    }
+}
+
+impl From<reqwest::Error> for GigaError {
+    fn from(value: reqwest::Error) -> Self {
+        if value.is_timeout() {
+            Self::Timeout(value)
+        } else {
+            Self::Request(value)
+        }
+    }
+}
+
+impl From<ApiError> for GigaError {
+    fn from(value: ApiError) -> Self {
+        match value.http_status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Unauthorized(value),
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited(value),
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Self::Validation(value),
+            StatusCode::PAYMENT_REQUIRED => Self::QuotaExceeded(value),
+            StatusCode::NOT_FOUND => Self::NotFound(value),
+            _ => Self::Api(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::{ApiError, ErrorBody, GigaError};
+
+    fn from_status(http_status: StatusCode) -> GigaError {
+        GigaError::from(ApiError {
+            http_status,
+            status: None,
+            message: None,
+            retry_after: None,
+        })
+    }
+
+    #[test]
+    fn statuses_map_to_variants() {
+        use StatusCode as S;
+
+        assert!(matches!(
+            from_status(S::UNAUTHORIZED),
+            GigaError::Unauthorized(_)
+        ));
+        assert!(matches!(
+            from_status(S::FORBIDDEN),
+            GigaError::Unauthorized(_)
+        ));
+        assert!(matches!(
+            from_status(S::TOO_MANY_REQUESTS),
+            GigaError::RateLimited(_)
+        ));
+        assert!(matches!(
+            from_status(S::BAD_REQUEST),
+            GigaError::Validation(_)
+        ));
+        assert!(matches!(
+            from_status(S::UNPROCESSABLE_ENTITY),
+            GigaError::Validation(_)
+        ));
+        assert!(matches!(
+            from_status(S::PAYMENT_REQUIRED),
+            GigaError::QuotaExceeded(_)
+        ));
+        assert!(matches!(from_status(S::NOT_FOUND), GigaError::NotFound(_)));
+        assert!(matches!(
+            from_status(S::INTERNAL_SERVER_ERROR),
+            GigaError::Api(_)
+        ));
+        assert!(matches!(from_status(S::BAD_GATEWAY), GigaError::Api(_)));
+
+        assert_eq!(from_status(S::NOT_FOUND).http_status(), Some(S::NOT_FOUND));
+    }
+
+    #[test]
+    fn json_error_body() {
+        let body = ErrorBody::from_text(r#"{"status": 401, "message": "Token has expired"}"#);
+        assert_eq!(body.status, Some(401));
+        assert_eq!(body.message.as_deref(), Some("Token has expired"));
+    }
+
+    #[test]
+    fn non_json_error_body_is_kept_as_is() {
+        let body = ErrorBody::from_text("<html><body>502 Bad Gateway</body></html>\n");
+        assert_eq!(body.status, None);
+        assert_eq!(
+            body.message.as_deref(),
+            Some("<html><body>502 Bad Gateway</body></html>")
+        );
+
+        assert_eq!(ErrorBody::from_text("  ").message, None);
+    }
+}
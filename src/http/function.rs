@@ -0,0 +1,186 @@
+use serde::{
+    de::{DeserializeOwned, Error},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::Value;
+
+use crate::error::{GigaError, Result as GigaResult};
+
+/// Example of a user request and the arguments the function should be called with, helps the model to call the function correctly
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FewShotExample {
+    pub request: String,
+    pub params: Value,
+}
+
+/// Description of a function, that the model is allowed to call
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON schema of the arguments
+    pub parameters: Value,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub few_shot_examples: Vec<FewShotExample>,
+    /// JSON schema of the value the function returns
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_parameters: Option<Value>,
+}
+
+impl FunctionDefinition {
+    pub fn new(name: &str, parameters: Value) -> Self {
+        Self {
+            name: name.to_owned(),
+            description: None,
+            parameters,
+            few_shot_examples: Vec::new(),
+            return_parameters: None,
+        }
+    }
+    pub fn set_description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_owned());
+        self
+    }
+    pub fn add_example(mut self, request: &str, params: Value) -> Self {
+        self.few_shot_examples.push(FewShotExample {
+            request: request.to_owned(),
+            params,
+        });
+        self
+    }
+    pub fn set_return_parameters(mut self, return_parameters: Value) -> Self {
+        self.return_parameters = Some(return_parameters);
+        self
+    }
+}
+
+#[cfg(feature = "derive")]
+impl FunctionDefinition {
+    /// Create a definition out of a type deriving `JsonSchema`, its fields become the parameters and its doc comment becomes the description
+    pub fn from_type<T: schemars::JsonSchema>(name: &str) -> Self {
+        let root = schemars::gen::SchemaSettings::draft07()
+            .with(|settings| {
+                settings.inline_subschemas = true;
+                settings.option_add_null_type = false;
+                settings.meta_schema = None;
+            })
+            .into_generator()
+            .into_root_schema_for::<T>();
+
+        let mut parameters = serde_json::to_value(root.schema).unwrap_or_default();
+        let description = parameters.as_object_mut().and_then(|schema| {
+            schema.remove("title");
+            schema.remove("description")
+        });
+
+        let mut definition = Self::new(name, parameters);
+        definition.description = description.and_then(|val| val.as_str().map(str::to_owned));
+        definition
+    }
+}
+
+/// Function call, that the model wants to be made
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+impl FunctionCall {
+    /// Deserializes the arguments the model generated into a Rust type
+    pub fn parse_arguments<T: DeserializeOwned>(&self) -> GigaResult<T> {
+        serde_json::from_value(self.arguments.clone()).map_err(|source| {
+            GigaError::InvalidArguments {
+                name: self.name.clone(),
+                source,
+            }
+        })
+    }
+}
+
+/// Controls whether and which function the model calls
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FunctionCallMode {
+    /// The model decides on its own
+    Auto,
+    /// The model never calls functions
+    None,
+    /// The model always calls the function with this name
+    Force(String),
+}
+
+impl Serialize for FunctionCallMode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Self::Auto => serializer.serialize_str("auto"),
+            Self::None => serializer.serialize_str("none"),
+            Self::Force(name) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("name", name)?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FunctionCallMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Mode(String),
+            Named { name: String },
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Mode(mode) if mode == "auto" => Ok(Self::Auto),
+            Repr::Mode(mode) if mode == "none" => Ok(Self::None),
+            Repr::Mode(mode) => Err(D::Error::custom(format!(
+                "unknown function_call mode: {}",
+                mode
+            ))),
+            Repr::Named { name } => Ok(Self::Force(name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::FunctionCallMode;
+
+    #[test]
+    fn function_call_mode_round_trip() {
+        let modes = [
+            (FunctionCallMode::Auto, json!("auto")),
+            (FunctionCallMode::None, json!("none")),
+            (
+                FunctionCallMode::Force("weather".to_owned()),
+                json!({"name": "weather"}),
+            ),
+        ];
+        for (mode, value) in modes {
+            assert_eq!(serde_json::to_value(&mode).unwrap(), value);
+            assert_eq!(
+                serde_json::from_value::<FunctionCallMode>(value).unwrap(),
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_function_call_mode_is_rejected() {
+        let err = serde_json::from_value::<FunctionCallMode>(json!("always")).unwrap_err();
+        assert!(err.to_string().contains("always"));
+    }
+}
@@ -2,24 +2,45 @@ use std::fmt::Display;
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::{GigaError, Result};
+
+use super::function::{FunctionCall, FunctionCallMode, FunctionDefinition};
+
 /// Roles that are used by GigaChat API
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Role {
+    #[serde(rename = "system")]
+    System,
     #[serde(rename = "user")]
     User,
     #[serde(rename = "assistant")]
     Assistant,
+    /// Result of a function call, sent back to the model
+    #[serde(rename = "function")]
+    Function,
+    /// Intermediate message, sent by the model while a built-in function is running
+    #[serde(rename = "function_in_progress")]
+    FunctionInProgress,
 }
 
 impl Display for Role {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
+            Self::System => {
+                write!(f, "system")
+            }
             Self::User => {
                 write!(f, "user")
             }
             Self::Assistant => {
                 write!(f, "assistant")
             }
+            Self::Function => {
+                write!(f, "function")
+            }
+            Self::FunctionInProgress => {
+                write!(f, "function_in_progress")
+            }
         }
     }
 }
@@ -27,10 +48,21 @@ impl Display for Role {
 /// Struct that is returned by GigaChat API as a Message
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Message {
+    #[serde(default)]
     pub content: String,
     pub role: Role,
-    #[serde(skip_serializing_if = "Vec::is_empty", skip_deserializing)]
+    /// IDs of the files from the storage, that the message refers to
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     attachments: Vec<String>,
+    /// Name of the function, which result the message carries
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub name: Option<String>,
+    /// Function the model wants to be called, set when `finish_reason` is `function_call`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub function_call: Option<FunctionCall>,
+    /// Identifier, that links a function call to the messages around it, has to be sent back unchanged
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub functions_state_id: Option<String>,
 }
 
 impl Message {
@@ -39,62 +71,96 @@ impl Message {
             content: content.to_owned(),
             role,
             attachments: vec![],
+            name: None,
+            function_call: None,
+            functions_state_id: None,
         }
     }
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(content: &str) -> Self {
-        Self {
-            content: content.to_owned(),
-            role: Role::User,
-            attachments: vec![],
-        }
+        Self::new(content, Role::User)
     }
     pub fn from_tuple(contents: &(&str, Role)) -> Self {
-        Self {
-            content: contents.0.to_owned(),
-            role: Role::User,
-            attachments: vec![],
-        }
+        Self::new(contents.0, Role::User)
+    }
+    /// Create a message with the result of a function call, that is sent back to the model
+    pub fn function_result(name: &str, result: &serde_json::Value) -> Self {
+        let mut message = Self::new(&result.to_string(), Role::Function);
+        message.name = Some(name.to_owned());
+        message
     }
     pub fn add_attachment(&mut self, attachment_id: &str) {
         self.attachments.push(attachment_id.to_owned());
     }
+    /// Attaches several files from the storage to the message
+    pub fn with_attachments(mut self, attachment_ids: &[&str]) -> Self {
+        self.attachments
+            .extend(attachment_ids.iter().map(|id| id.to_string()));
+        self
+    }
+    /// Returns IDs of the attached files
+    pub fn attachments(&self) -> &[String] {
+        &self.attachments
+    }
+    pub fn clear_attachments(&mut self) {
+        self.attachments.clear();
+    }
+
+    /// Returns IDs of the images the model generated, which come as `<img src="file_id">` in the content
+    ///
+    /// The images can be downloaded with `GigaClient::download_file`
+    pub fn image_ids(&self) -> Vec<String> {
+        let mut ids = Vec::new();
+        let mut rest = self.content.as_str();
+        while let Some(start) = rest.find("<img") {
+            rest = &rest[start + "<img".len()..];
+            let tag = &rest[..rest.find('>').unwrap_or(rest.len())];
+            let Some(src) = tag.find("src=") else {
+                continue;
+            };
+            let value = &tag[src + "src=".len()..];
+            let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+                continue;
+            };
+            let value = &value[1..];
+            if let Some(end) = value.find(quote) {
+                ids.push(value[..end].to_owned());
+            }
+        }
+        ids
+    }
+
+    /// Appends a streamed fragment to the message, which is being assembled
+    pub(crate) fn push_fragment(&mut self, fragment: Message) {
+        self.content.push_str(&fragment.content);
+        if fragment.function_call.is_some() {
+            self.function_call = fragment.function_call;
+        }
+        if fragment.functions_state_id.is_some() {
+            self.functions_state_id = fragment.functions_state_id;
+        }
+    }
 }
 
 impl From<String> for Message {
     fn from(value: String) -> Self {
-        Self {
-            content: value,
-            role: Role::User,
-            attachments: vec![],
-        }
+        Self::new(&value, Role::User)
     }
 }
 
 impl From<&str> for Message {
     fn from(value: &str) -> Self {
-        Self {
-            content: value.to_owned(),
-            role: Role::User,
-            attachments: vec![],
-        }
+        Self::new(value, Role::User)
     }
 }
 impl From<(&str, Role)> for Message {
     fn from(value: (&str, Role)) -> Self {
-        Self {
-            content: value.0.to_owned(),
-            role: value.1,
-            attachments: vec![],
-        }
+        Self::new(value.0, value.1)
     }
 }
 impl From<(String, Role)> for Message {
     fn from(value: (String, Role)) -> Self {
-        Self {
-            content: value.0,
-            role: value.1,
-            attachments: vec![],
-        }
+        Self::new(&value.0, value.1)
     }
 }
 
@@ -106,6 +172,17 @@ pub struct MessageConfig {
     pub stream: Option<bool>,
     pub max_tokens: Option<u32>,
     pub repetition_penalty: Option<f32>,
+    /// How many alternative answers the model generates, 1..4
+    pub n: Option<u32>,
+    /// Minimal interval in seconds between streamed chunks
+    pub update_interval: Option<f32>,
+    /// Whether the API censors the answers, can only be disabled for some accounts
+    pub profanity_check: Option<bool>,
+    /// Functions, that are sent along with every request
+    pub functions: Vec<FunctionDefinition>,
+    pub function_call: Option<FunctionCallMode>,
+    /// Whether attachments of a message are checked with `GigaClient::validate_attachments` before sending it, true by default
+    pub validate_attachments: bool,
 }
 
 impl Default for MessageConfig {
@@ -117,6 +194,12 @@ impl Default for MessageConfig {
             stream: None,
             max_tokens: None,
             repetition_penalty: None,
+            n: None,
+            update_interval: None,
+            profanity_check: None,
+            functions: Vec::new(),
+            function_call: None,
+            validate_attachments: true,
         }
     }
 }
@@ -129,18 +212,17 @@ pub struct MessageConfigBuilder {
     pub stream: Option<bool>,
     pub max_tokens: Option<u32>,
     pub repetition_penalty: Option<f32>,
+    pub n: Option<u32>,
+    pub update_interval: Option<f32>,
+    pub profanity_check: Option<bool>,
+    pub functions: Vec<FunctionDefinition>,
+    pub function_call: Option<FunctionCallMode>,
+    pub validate_attachments: Option<bool>,
 }
 
 impl MessageConfigBuilder {
     pub fn new() -> Self {
-        Self {
-            model: None,
-            temperature: None,
-            top_p: None,
-            stream: None,
-            max_tokens: None,
-            repetition_penalty: None,
-        }
+        Self::default()
     }
     pub fn set_model(mut self, model: &str) -> Self {
         self.model = Some(model.to_owned());
@@ -166,18 +248,155 @@ impl MessageConfigBuilder {
         self.repetition_penalty = Some(penalty);
         self
     }
-    pub fn build(&self) -> MessageConfig {
-        MessageConfig {
-            model: self
-                .model
-                .as_ref()
-                .expect("Model should be set")
-                .to_string(),
+    /// Sets how many alternative answers the model generates, they are available in `ChatResponse::choices`
+    pub fn set_n(mut self, n: u32) -> Self {
+        self.n = Some(n);
+        self
+    }
+    /// Sets the minimal interval in seconds between streamed chunks
+    pub fn set_update_interval(mut self, interval: f32) -> Self {
+        self.update_interval = Some(interval);
+        self
+    }
+    pub fn set_profanity_check(mut self, check: bool) -> Self {
+        self.profanity_check = Some(check);
+        self
+    }
+    /// Disables the attachment check, which costs a file info request per attachment, e.g. if the files are known to be valid
+    pub fn set_validate_attachments(mut self, validate: bool) -> Self {
+        self.validate_attachments = Some(validate);
+        self
+    }
+    pub fn add_function(mut self, function: FunctionDefinition) -> Self {
+        self.functions.push(function);
+        self
+    }
+    pub fn set_function_call(mut self, mode: FunctionCallMode) -> Self {
+        self.function_call = Some(mode);
+        self
+    }
+    /// Builds the config, checking that the parameters are within the ranges the API accepts
+    pub fn build(&self) -> Result<MessageConfig> {
+        let model = self
+            .model
+            .clone()
+            .ok_or_else(|| GigaError::Config("Model should be set".to_owned()))?;
+
+        if let Some(temperature) = self.temperature {
+            check(temperature > 0.0, "temperature must be greater than 0")?;
+        }
+        if let Some(top_p) = self.top_p {
+            check((0.0..=1.0).contains(&top_p), "top_p must be in 0..1")?;
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            check(max_tokens > 0, "max_tokens must be greater than 0")?;
+        }
+        if let Some(penalty) = self.repetition_penalty {
+            check(penalty > 0.0, "repetition_penalty must be greater than 0")?;
+        }
+        if let Some(n) = self.n {
+            check((1..=4).contains(&n), "n must be in 1..4")?;
+        }
+        if let Some(interval) = self.update_interval {
+            check(interval >= 0.0, "update_interval must not be negative")?;
+        }
+
+        Ok(MessageConfig {
+            model,
             temperature: self.temperature,
             top_p: self.top_p,
             stream: self.stream,
             max_tokens: self.max_tokens,
             repetition_penalty: self.repetition_penalty,
-        }
+            n: self.n,
+            update_interval: self.update_interval,
+            profanity_check: self.profanity_check,
+            functions: self.functions.clone(),
+            function_call: self.function_call.clone(),
+            validate_attachments: self.validate_attachments.unwrap_or(true),
+        })
+    }
+}
+
+/// Turns a failed parameter check into a configuration error
+fn check(valid: bool, message: &str) -> Result<()> {
+    if valid {
+        Ok(())
+    } else {
+        Err(GigaError::Config(message.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::GigaError;
+
+    use super::{Message, MessageConfigBuilder};
+
+    fn image_ids(content: &str) -> Vec<String> {
+        Message::from_str(content).image_ids()
+    }
+
+    fn builder() -> MessageConfigBuilder {
+        MessageConfigBuilder::new().set_model("GigaChat")
+    }
+
+    fn is_config_error(builder: MessageConfigBuilder) -> bool {
+        matches!(builder.build(), Err(GigaError::Config(_)))
+    }
+
+    #[test]
+    fn double_and_single_quotes() {
+        assert_eq!(
+            image_ids("Here <img src=\"abc-1\" fuse=\"true\"/> and <img src='x2'>"),
+            ["abc-1", "x2"]
+        );
+    }
+
+    #[test]
+    fn src_is_not_the_first_attribute() {
+        assert_eq!(image_ids("<img alt=\"cat\" src=\"id\">"), ["id"]);
+    }
+
+    #[test]
+    fn tags_without_src_are_skipped() {
+        assert_eq!(image_ids("<img alt=\"cat\"> <img src=\"id\">"), ["id"]);
+    }
+
+    #[test]
+    fn malformed_tags_are_skipped() {
+        assert!(image_ids("<img src=id>").is_empty());
+        assert!(image_ids("<img src=\"unclosed").is_empty());
+        assert!(image_ids("text <img").is_empty());
+        assert!(image_ids("no images").is_empty());
+    }
+
+    #[test]
+    fn top_p_bounds_are_inclusive() {
+        assert!(builder().set_top_p(0.0).build().is_ok());
+        assert!(builder().set_top_p(1.0).build().is_ok());
+        assert!(is_config_error(builder().set_top_p(1.1)));
+        assert!(is_config_error(builder().set_top_p(-0.1)));
+    }
+
+    #[test]
+    fn nan_is_rejected() {
+        assert!(is_config_error(builder().set_top_p(f32::NAN)));
+        assert!(is_config_error(builder().set_temp(f32::NAN)));
+        assert!(is_config_error(builder().set_rep_penalty(f32::NAN)));
+        assert!(is_config_error(builder().set_update_interval(f32::NAN)));
+    }
+
+    #[test]
+    fn n_must_be_in_range() {
+        assert!(builder().set_n(1).build().is_ok());
+        assert!(builder().set_n(4).build().is_ok());
+        assert!(is_config_error(builder().set_n(0)));
+        assert!(is_config_error(builder().set_n(5)));
+    }
+
+    #[test]
+    fn model_is_required() {
+        assert!(is_config_error(MessageConfigBuilder::new()));
     }
 }
@@ -1,3 +1,4 @@
+pub mod function;
 pub mod message;
 pub mod request;
-pub mod response;
\ No newline at end of file
+pub mod response;
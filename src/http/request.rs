@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 
-use super::message::Message;
+use super::{
+    function::{FunctionCallMode, FunctionDefinition},
+    message::Message,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChatRequest {
@@ -17,4 +20,26 @@ pub struct ChatRequest {
     pub max_tokens: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub repetition_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub n: Option<u32>, // 1..4
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub update_interval: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub profanity_check: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub functions: Vec<FunctionDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub function_call: Option<FunctionCallMode>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmbeddingsRequest {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokensCountRequest {
+    pub model: String,
+    pub input: Vec<String>,
 }
@@ -1,21 +1,55 @@
+use std::ops::AddAssign;
+
 use serde::{Deserialize, Serialize};
 
-use super::message::Message;
+use crate::error::{GigaError, Result};
 
-#[derive(Deserialize, Debug)]
+use super::{
+    function::FunctionCall,
+    message::{Message, Role},
+};
+
+/// Amount of tokens spent on a completion
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
 pub struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
 }
-#[derive(Deserialize, Debug)]
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.prompt_tokens += rhs.prompt_tokens;
+        self.completion_tokens += rhs.completion_tokens;
+        self.total_tokens += rhs.total_tokens;
+    }
+}
+
+/// Reason, why the model stopped generating
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The answer is complete
+    Stop,
+    /// The answer is cut off, because of max_tokens or the context size
+    Length,
+    /// The model wants a function to be called, see `Message::function_call`
+    FunctionCall,
+    /// The request or the answer touches a blacklisted topic
+    Blacklist,
+    Error,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct Choice {
     pub message: Message,
     pub index: u32,
-    pub finish_reason: String,
+    pub finish_reason: FinishReason,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct ChatResponse {
     pub choices: Vec<Choice>,
     pub created: u64,
@@ -24,6 +58,109 @@ pub struct ChatResponse {
     pub usage: Usage,
 }
 
+impl ChatResponse {
+    /// Returns the message of the last choice, which is the answer unless several were requested
+    pub fn message(&self) -> Option<&Message> {
+        self.choices.last().map(|choice| &choice.message)
+    }
+
+    /// Returns why the model stopped generating the last choice, e.g. `FinishReason::Length` if the answer is cut off
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.choices.last().map(|choice| &choice.finish_reason)
+    }
+
+    /// Same as `message`, but a response without choices is an error
+    pub(crate) fn answer(&self) -> Result<Message> {
+        self.message().cloned().ok_or_else(|| {
+            GigaError::UnexpectedResponse("There is no choice from the AI".to_owned())
+        })
+    }
+
+    /// Response, which streamed chunks are pushed into
+    pub(crate) fn empty(model: &str) -> Self {
+        Self {
+            choices: Vec::new(),
+            created: 0,
+            model: model.to_owned(),
+            object: "chat.completion".to_owned(),
+            usage: Usage::default(),
+        }
+    }
+
+    /// Appends a streamed chunk to the response, which is being assembled
+    pub(crate) fn push_chunk(&mut self, chunk: ChatStreamChunk) {
+        self.created = chunk.created;
+        self.model = chunk.model;
+        // Usage of a chunk is the total so far, so the last one replaces the previous ones
+        if let Some(usage) = chunk.usage {
+            self.usage = usage;
+        }
+
+        for choice in chunk.choices {
+            let fragment: Message = choice.delta.into();
+            let idx = match self.choices.iter().position(|c| c.index == choice.index) {
+                Some(idx) => {
+                    self.choices[idx].message.push_fragment(fragment);
+                    idx
+                }
+                None => {
+                    self.choices.push(Choice {
+                        message: fragment,
+                        index: choice.index,
+                        finish_reason: FinishReason::Unknown,
+                    });
+                    self.choices.len() - 1
+                }
+            };
+            if let Some(finish_reason) = choice.finish_reason {
+                self.choices[idx].finish_reason = finish_reason;
+            }
+        }
+    }
+}
+
+/// Incremental message fragment, sent by GigaChat API when streaming is enabled
+#[derive(Deserialize, Debug, Clone)]
+pub struct Delta {
+    #[serde(default)]
+    pub content: String,
+    pub role: Option<Role>,
+    pub function_call: Option<FunctionCall>,
+    pub functions_state_id: Option<String>,
+}
+
+impl From<Delta> for Message {
+    fn from(value: Delta) -> Self {
+        let mut message = Message::new(&value.content, value.role.unwrap_or(Role::Assistant));
+        message.function_call = value.function_call;
+        message.functions_state_id = value.functions_state_id;
+        message
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamChoice {
+    pub delta: Delta,
+    pub index: u32,
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// A single `data:` event of a streamed chat completion
+#[derive(Deserialize, Debug)]
+pub struct ChatStreamChunk {
+    pub choices: Vec<StreamChoice>,
+    pub created: u64,
+    pub model: String,
+    pub object: String,
+    pub usage: Option<Usage>,
+}
+
+/// Envelope used by the list endpoints, e.g. models and files
+#[derive(Deserialize, Debug)]
+pub(crate) struct DataList<T> {
+    pub data: Vec<T>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Model {
     pub id: String,
@@ -33,3 +170,68 @@ pub struct Model {
     #[serde(alias = "type")]
     pub type_: String,
 }
+
+impl Model {
+    /// Whether the model generates chat completions, e.g. `GigaChat`
+    pub fn is_chat(&self) -> bool {
+        self.type_ == "chat"
+    }
+    /// Whether the model generates embeddings, e.g. `Embeddings` or `EmbeddingsGigaR`
+    pub fn is_embedder(&self) -> bool {
+        self.type_ == "embedder"
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: u32,
+}
+
+/// Vector representation of a single input
+#[derive(Deserialize, Debug, Clone)]
+pub struct Embedding {
+    pub object: String,
+    pub embedding: Vec<f32>,
+    /// Position of the input, the embedding was made for
+    pub index: u32,
+    pub usage: Option<EmbeddingUsage>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Embeddings {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+}
+
+impl Embeddings {
+    /// Total amount of tokens, that the inputs took
+    pub fn prompt_tokens(&self) -> u32 {
+        self.data
+            .iter()
+            .filter_map(|embedding| embedding.usage.as_ref())
+            .map(|usage| usage.prompt_tokens)
+            .sum()
+    }
+}
+
+/// Amount of tokens and characters in a single input
+#[derive(Deserialize, Debug, Clone)]
+pub struct TokensCount {
+    pub object: String,
+    pub tokens: u32,
+    pub characters: u32,
+}
+
+/// Remaining tokens of a model (or a group of models), that the account can use
+#[derive(Deserialize, Debug, Clone)]
+pub struct Balance {
+    pub usage: String,
+    pub value: u64,
+}
+
+/// Envelope of the balance endpoint
+#[derive(Deserialize, Debug)]
+pub(crate) struct BalanceList {
+    pub balance: Vec<Balance>,
+}
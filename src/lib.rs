@@ -1,4 +1,9 @@
 //! Gigalib is an open-source async GigaChat API wrapper library, with a focus on ease of use and yada yada u know
 
 pub mod controllers;
+pub mod error;
 pub mod http;
+
+/// Re-exported, so `JsonSchema` can be derived for function parameters without depending on schemars directly
+#[cfg(feature = "derive")]
+pub use schemars;